@@ -0,0 +1,342 @@
+//! `backups.rs`: Keeps rotating copies of the encrypted vault file, each with
+//! the change journal it had, so the vault can be rebuilt as of any time the
+//! backups cover.
+
+use crate::crypto::EncryptedVault;
+use crate::journal;
+use crate::models::BackupSettings;
+use crate::storage::{self, StorageError};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const BACKUP_DIR: &str = "backups";
+/// Backup names start with the vault file's stem, so vaults sharing a folder,
+/// and with it the backup folder, each only see their own backups. This stem
+/// stands in for a vault path without one.
+const DEFAULT_BACKUP_STEM: &str = "vault";
+const BACKUP_SUFFIX: &str = ".json";
+/// UTC time of the backup, used in its file name so names sort chronologically.
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupInfo {
+    pub file_name: String,
+    /// RFC 3339 timestamp.
+    pub created_at: String,
+    pub size: u64,
+}
+
+struct Backup {
+    file_name: String,
+    created_at: DateTime<Utc>,
+    size: u64,
+}
+
+// --- Public Functions ---
+
+/// The backup folder sits next to the vault file.
+pub fn backup_dir(vault_path: &Path) -> PathBuf {
+    vault_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(BACKUP_DIR)
+}
+
+/// Copies the vault file and its journal as they are into the backup folder, then
+/// prunes old backups according to `settings`. Does nothing before the first save.
+pub fn back_up_vault(vault_path: &Path, settings: &BackupSettings) -> Result<(), StorageError> {
+    if !settings.enabled || !vault_path.exists() {
+        return Ok(());
+    }
+
+    back_up(vault_path)?;
+    // A backup that can't be removed is no reason to fail the save.
+    if let Err(e) = prune_backups(vault_path, settings, Utc::now()) {
+        warn!("Failed to prune old backups: {}", e);
+    }
+    Ok(())
+}
+
+/// Lists the vault's backups, newest first.
+pub fn list_backups(vault_path: &Path) -> Result<Vec<BackupInfo>, StorageError> {
+    let backups = find_backups(vault_path)?
+        .into_iter()
+        .map(|backup| BackupInfo {
+            file_name: backup.file_name,
+            created_at: backup.created_at.to_rfc3339(),
+            size: backup.size,
+        })
+        .collect();
+    Ok(backups)
+}
+
+/// Replaces the vault file with one of its backups. The current file is backed
+/// up first, regardless of the retention settings, so a restore can be undone.
+pub fn restore_backup(vault_path: &Path, file_name: &str) -> Result<(), StorageError> {
+    // Only names of files in the backup folder are accepted, never paths.
+    let backup_path = backup_dir(vault_path).join(file_name);
+    if backup_time(vault_path, file_name).is_none() || !backup_path.is_file() {
+        return Err(StorageError::BackupNotFound(file_name.to_string()));
+    }
+
+    let contents = fs::read(&backup_path)?;
+    // Refuse a damaged backup before the current vault is touched.
+    serde_json::from_slice::<EncryptedVault>(&contents).map_err(|e| {
+        error!("Backup {} is not a valid vault file: {}", file_name, e);
+        StorageError::from(e)
+    })?;
+
+    if vault_path.exists() {
+        back_up(vault_path)?;
+    }
+    storage::write_atomically(vault_path, &contents)?;
+    // The backup's journal holds the changes made after it was written. A
+    // journal of the replaced file would be discarded on the next load anyway.
+    let backup_journal = journal::journal_path(&backup_path);
+    let vault_journal = journal::journal_path(vault_path);
+    if backup_journal.is_file() {
+        storage::write_atomically(&vault_journal, &fs::read(&backup_journal)?)?;
+    } else if vault_journal.exists() {
+        fs::remove_file(&vault_journal)?;
+    }
+    info!("Vault restored from backup {}", file_name);
+    Ok(())
+}
+
+/// Copies the vault file and its journal into the backup folder regardless of
+/// the backup settings, e.g. before a schema migration. Returns the backup's path.
+pub fn back_up(vault_path: &Path) -> Result<PathBuf, StorageError> {
+    let dir = backup_dir(vault_path);
+    fs::create_dir_all(&dir)?;
+
+    let file_name = format!(
+        "{}{}{}",
+        backup_prefix(vault_path),
+        Utc::now().format(BACKUP_TIMESTAMP_FORMAT),
+        BACKUP_SUFFIX
+    );
+    let backup_path = dir.join(file_name);
+    let contents = fs::read(vault_path)?;
+    // The journal goes first, so a backup is never left without its changes.
+    let vault_journal = journal::journal_path(vault_path);
+    if vault_journal.is_file() {
+        let journal_contents = fs::read(&vault_journal)?;
+        storage::write_atomically(&journal::journal_path(&backup_path), &journal_contents)?;
+    }
+    storage::write_atomically(&backup_path, &contents).map_err(|e| {
+        error!("Failed to back up vault to {}: {}", backup_path.display(), e);
+        e
+    })?;
+    Ok(backup_path)
+}
+
+/// Paths and times of the vault's backups, oldest first.
+pub(crate) fn backup_files(vault_path: &Path) -> Result<Vec<(PathBuf, DateTime<Utc>)>, StorageError> {
+    let dir = backup_dir(vault_path);
+    let mut files: Vec<_> = find_backups(vault_path)?
+        .into_iter()
+        .map(|backup| (dir.join(backup.file_name), backup.created_at))
+        .collect();
+    files.reverse();
+    Ok(files)
+}
+
+// --- Helpers ---
+
+/// E.g. `vault-` for `vault.json`.
+fn backup_prefix(vault_path: &Path) -> String {
+    let stem = vault_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| DEFAULT_BACKUP_STEM.to_string());
+    format!("{}-", stem)
+}
+
+/// When the backup was taken, if `file_name` is a backup of this vault.
+fn backup_time(vault_path: &Path, file_name: &str) -> Option<DateTime<Utc>> {
+    let timestamp = file_name
+        .strip_prefix(&backup_prefix(vault_path))?
+        .strip_suffix(BACKUP_SUFFIX)?;
+    NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// The vault's backups in the backup folder, newest first. Other files,
+/// including backups of other vaults, are ignored.
+fn find_backups(vault_path: &Path) -> Result<Vec<Backup>, StorageError> {
+    let dir = backup_dir(vault_path);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(created_at) = backup_time(vault_path, &file_name) {
+            backups.push(Backup {
+                file_name,
+                created_at,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_at));
+    Ok(backups)
+}
+
+/// Keeps the `keep_last` newest backups plus the newest backup of each of the
+/// last `keep_daily_days` days, and removes the rest.
+fn prune_backups(
+    vault_path: &Path,
+    settings: &BackupSettings,
+    now: DateTime<Utc>,
+) -> Result<(), StorageError> {
+    let dir = backup_dir(vault_path);
+    let daily_cutoff = now - chrono::Duration::days(i64::from(settings.keep_daily_days));
+    let mut days_kept = HashSet::new();
+
+    for (index, backup) in find_backups(vault_path)?.into_iter().enumerate() {
+        let newest_of_day =
+            backup.created_at > daily_cutoff && days_kept.insert(backup.created_at.date_naive());
+        if index < settings.keep_last as usize || newest_of_day {
+            continue;
+        }
+        let backup_path = dir.join(&backup.file_name);
+        let backup_journal = journal::journal_path(&backup_path);
+        if backup_journal.exists() {
+            fs::remove_file(backup_journal)?;
+        }
+        fs::remove_file(backup_path)?;
+        info!("Removed old backup {}", backup.file_name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for one test's vault files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("accman-backups-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn keeps_backups_of_vaults_in_one_folder_apart() {
+        let dir = test_dir("apart");
+        let first = dir.join("first.json");
+        let second = dir.join("second.json");
+        fs::write(&first, b"{}").unwrap();
+        fs::write(&second, b"{}").unwrap();
+        let first_backup = back_up(&first).unwrap();
+        back_up(&second).unwrap();
+
+        let listed = list_backups(&first).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(first_backup.file_name().unwrap().to_string_lossy(), listed[0].file_name);
+        assert!(listed[0].file_name.starts_with("first-"));
+        assert_eq!(backup_files(&second).unwrap().len(), 1);
+
+        // Another vault's backup can't be restored over this one.
+        let second_name = list_backups(&second).unwrap()[0].file_name.clone();
+        assert!(matches!(
+            restore_backup(&first, &second_name),
+            Err(StorageError::BackupNotFound(_))
+        ));
+
+        // Pruning one vault's backups leaves the other's alone.
+        let settings = BackupSettings {
+            enabled: true,
+            keep_last: 0,
+            keep_daily_days: 0,
+        };
+        prune_backups(&first, &settings, Utc::now()).unwrap();
+        assert!(list_backups(&first).unwrap().is_empty());
+        assert_eq!(list_backups(&second).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes an empty backup of `vault_path` as if taken at `created_at`.
+    fn backup_at(vault_path: &Path, created_at: DateTime<Utc>) {
+        let dir = backup_dir(vault_path);
+        fs::create_dir_all(&dir).unwrap();
+        let file_name = format!(
+            "{}{}{}",
+            backup_prefix(vault_path),
+            created_at.format(BACKUP_TIMESTAMP_FORMAT),
+            BACKUP_SUFFIX
+        );
+        fs::write(dir.join(file_name), b"{}").unwrap();
+    }
+
+    #[test]
+    fn keeps_the_latest_backups_and_one_per_day() {
+        let dir = test_dir("prune");
+        let vault_path = dir.join("vault.json");
+        // Midday, so the hours below fall on the days the comment says.
+        let now = NaiveDateTime::parse_from_str("20240610T120000.000Z", BACKUP_TIMESTAMP_FORMAT)
+            .unwrap()
+            .and_utc();
+        let hours_ago = |hours: i64| now - chrono::Duration::hours(hours);
+        // Three today, one on each of two earlier days, and one from long ago.
+        for hours in [1, 2, 3, 25, 26, 49, 24 * 40] {
+            backup_at(&vault_path, hours_ago(hours));
+        }
+        let settings = BackupSettings {
+            enabled: true,
+            keep_last: 2,
+            keep_daily_days: 3,
+        };
+
+        prune_backups(&vault_path, &settings, now).unwrap();
+        let kept: Vec<DateTime<Utc>> = find_backups(&vault_path)
+            .unwrap()
+            .into_iter()
+            .map(|backup| backup.created_at)
+            .collect();
+        let expected: Vec<DateTime<Utc>> = [1, 2, 25, 49].into_iter().map(hours_ago).collect();
+        assert_eq!(kept, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restores_a_backup_with_its_journal() {
+        let dir = test_dir("restore");
+        let vault_path = dir.join("vault.json");
+        let vault_journal = journal::journal_path(&vault_path);
+        let encrypted = |marker: u8| format!("{{\"salt\":\"\",\"ciphertext\":[{}]}}", marker);
+        fs::write(&vault_path, encrypted(1)).unwrap();
+        fs::write(&vault_journal, b"old journal").unwrap();
+        let backup = back_up(&vault_path).unwrap();
+
+        fs::write(&vault_path, encrypted(2)).unwrap();
+        fs::remove_file(&vault_journal).unwrap();
+        // Backups taken within the same millisecond would share a name.
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let file_name = backup.file_name().unwrap().to_string_lossy().into_owned();
+        restore_backup(&vault_path, &file_name).unwrap();
+
+        assert_eq!(fs::read_to_string(&vault_path).unwrap(), encrypted(1));
+        assert_eq!(fs::read(&vault_journal).unwrap(), b"old journal");
+        // The replaced file was backed up first, so the restore can be undone.
+        let backups = list_backups(&vault_path).unwrap();
+        assert_eq!(backups.len(), 2);
+        let replaced = backup_dir(&vault_path).join(&backups[0].file_name);
+        assert_eq!(fs::read_to_string(replaced).unwrap(), encrypted(2));
+
+        assert!(matches!(
+            restore_backup(&vault_path, "../vault.json"),
+            Err(StorageError::BackupNotFound(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,30 @@
 //! `commands.rs`: Defines the Tauri commands exposed to the frontend.
 
-use crate::crypto::MasterPassword;
-use crate::models::{Account, Service, ServiceType, Settings, Vault};
+use crate::crypto::{
+    self, Cipher, CryptoError, EncryptionSettings, KdfAlgorithm, KdfCalibration, KdfParams,
+    KeyfileHash, MasterPassword, VaultKeys,
+};
+use crate::backups::{self, BackupInfo};
+use crate::history::{self, RecordChange};
+use crate::journal::{self, Journal, JournalEntry};
+use crate::merge::{self, ImportCounts, MergeDecision, MergePreview, RecordKind};
+use crate::migrations;
+use crate::models::{
+    Account, FieldValue, Service, ServiceType, Settings, Timestamps, TrashedItem, Vault,
+};
+use crate::query::{self, RecordQuery};
+use crate::registry::{self, RegistryState, VaultProfile, VaultRegistry};
 use crate::storage::{self, StorageError};
+use crate::undo::{UndoHistory, UndoState};
+use crate::vault_lock::{self, VaultLock};
 use crate::StoragePath;
 use crate::defaults; // Import the defaults module
+use crate::operations::{Operation, OperationError, TrashKey};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use tauri::State;
+use tauri::{AppHandle, Manager, State};
 use uuid;
 
 // --- State Management ---
@@ -14,10 +32,19 @@ use uuid;
 /// Manages the in-memory state of the decrypted vault.
 pub struct AppState(pub Mutex<Option<Vault>>);
 
-/// Manages the session's master password. This is cleared on lock.
+/// Manages the key material of the open vault: its data key and the key
+/// derived from the master password. The password itself is not kept; the
+/// keys are locked in memory and zeroized on lock. Also holds the vault lock,
+/// the change journal of the vault file and the changes that can be undone,
+/// while the vault is open, and another vault decrypted for a merge until it
+/// is applied.
 #[derive(Default)]
 pub struct SessionState {
-    pub master_password: Mutex<Option<MasterPassword>>,
+    pub vault_keys: Mutex<Option<VaultKeys>>,
+    pub vault_lock: Mutex<Option<VaultLock>>,
+    pub journal: Mutex<Option<Journal>>,
+    pub undo: Mutex<UndoHistory>,
+    pub pending_merge: Mutex<Option<Vault>>,
 }
 
 // --- Command Errors ---
@@ -26,10 +53,16 @@ pub struct SessionState {
 pub enum CommandError {
     #[error("Storage Error: {0}")]
     Storage(String),
+    #[error("Cryptography Error: {0}")]
+    Crypto(String),
     #[error("Vault is already unlocked.")]
     AlreadyUnlocked,
     #[error("Vault is locked or not yet created.")]
     VaultLocked,
+    #[error("The vault is open somewhere else: {0}")]
+    VaultInUse(String),
+    #[error("The vault file was changed outside AccMan. Reload it before saving.")]
+    VaultChangedOnDisk,
     #[error("A Service Type with ID '{0}' already exists.")]
     ServiceTypeExists(String),
     #[error("Service Type with ID '{0}' not found.")]
@@ -38,34 +71,187 @@ pub enum CommandError {
     ServiceNotFound(String),
     #[error("Account with ID '{0}' not found.")]
     AccountNotFound(String),
+    #[error("Field '{0}' had no such earlier value.")]
+    FieldValueNotFound(String),
+    #[error("Item with ID '{0}' is not in the trash.")]
+    NotInTrash(String),
+    #[error("A record with ID '{0}' already exists. Delete it before restoring this one.")]
+    RecordExists(String),
     #[error("The old password provided is incorrect.")]
     InvalidOldPassword,
+    #[error("There is no vault to merge. Preview the merge first.")]
+    NoPendingMerge,
+    #[error("Vault with ID '{0}' is not registered.")]
+    VaultNotRegistered(String),
+    #[error("A file already exists at '{0}'. Open it instead of creating a new vault.")]
+    VaultFileExists(String),
+    #[error("Vault name cannot be empty.")]
+    EmptyVaultName,
+    #[error("The active vault cannot be forgotten. Switch to another vault first.")]
+    ActiveVaultCannotBeForgotten,
+    #[error("'{0}' is not a valid date and time.")]
+    InvalidHistoryTime(String),
+    #[error("There are no backups of the vault from as early as {0}.")]
+    HistoryUnavailable(String),
+    #[error("The vault's history before {0} can't be read: its key was replaced since, e.g. when a recovery key was set up.")]
+    HistoryBeforeKeyRotation(String),
+    #[error("The command stopped before it finished: {0}")]
+    Interrupted(String),
+    #[error("There is nothing to undo.")]
+    NothingToUndo,
+    #[error("There is nothing to redo.")]
+    NothingToRedo,
 }
 
 impl From<StorageError> for CommandError {
     fn from(err: StorageError) -> Self {
-        CommandError::Storage(err.to_string())
+        match err {
+            StorageError::VaultInUse(owner) => CommandError::VaultInUse(owner),
+            StorageError::ChangedOnDisk => CommandError::VaultChangedOnDisk,
+            StorageError::HistoryUnavailable(time) => CommandError::HistoryUnavailable(time),
+            StorageError::HistoryBeforeKeyRotation(time) => CommandError::HistoryBeforeKeyRotation(time),
+            err => CommandError::Storage(err.to_string()),
+        }
+    }
+}
+
+impl From<OperationError> for CommandError {
+    fn from(err: OperationError) -> Self {
+        match err {
+            OperationError::ServiceTypeExists(id) => CommandError::ServiceTypeExists(id),
+            OperationError::ServiceTypeNotFound(id) => CommandError::ServiceTypeNotFound(id),
+            OperationError::ServiceNotFound(id) => CommandError::ServiceNotFound(id),
+            OperationError::AccountNotFound(id) => CommandError::AccountNotFound(id),
+            OperationError::NotInTrash(id) => CommandError::NotInTrash(id),
+            OperationError::RecordExists(id) => CommandError::RecordExists(id),
+        }
+    }
+}
+
+impl From<CryptoError> for CommandError {
+    fn from(err: CryptoError) -> Self {
+        CommandError::Crypto(err.to_string())
     }
 }
 
-// --- Helper for saving ---
-fn save_vault_with_session_password(
+// --- Helpers for saving ---
+
+/// Applies `operation` to the open vault, journals it and records it so it
+/// can be undone.
+fn apply_operation(
     path: &State<StoragePath>,
+    operation: Operation,
     app_state: &State<AppState>,
     session_state: &State<SessionState>,
 ) -> Result<(), CommandError> {
-    let vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    let mut vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
 
-    let password_guard = session_state.master_password.lock().unwrap();
-    let password = password_guard
-        .as_ref()
-        .ok_or(CommandError::VaultLocked)?;
+    let inverse = operation.inverse(vault);
+    journal_operation(path, &operation, vault, session_state)?;
+    session_state.undo.lock().unwrap().record(operation, inverse);
+    Ok(())
+}
 
-    storage::save_vault(path, vault, password)?;
+/// Applies `operation` to `vault`, the open vault, and appends it to the
+/// change journal. Once the journal is long enough, the vault is saved in
+/// full instead, which compacts it.
+fn journal_operation(
+    path: &State<StoragePath>,
+    operation: &Operation,
+    vault: &mut Vault,
+    session_state: &State<SessionState>,
+) -> Result<(), CommandError> {
+    let keys_guard = session_state.vault_keys.lock().unwrap();
+    let keys = keys_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let mut journal_guard = session_state.journal.lock().unwrap();
+    let journal = journal_guard.as_mut().ok_or(CommandError::VaultLocked)?;
+
+    // Full saves, which back up the file they replace, are rare with the
+    // journal, so the vault is also backed up before its first change in a
+    // session.
+    if !journal.backed_up() {
+        backups::back_up_vault(&path.get(), &vault.settings.backup)?;
+        journal.set_backed_up();
+    }
+    operation.apply(vault)?;
+    if journal.needs_compaction() {
+        info!("Compacting the change journal after {} entries", journal.entries());
+        let compacted = storage::save_vault(path, vault, keys, Some(journal))?;
+        *journal = compacted;
+    } else {
+        journal.append(&path.get(), operation, keys)?;
+    }
+    Ok(())
+}
+
+/// Saves `vault` in full unless the file or its journal were changed since
+/// this session last read or wrote them, so changes made elsewhere are never
+/// silently overwritten.
+fn save_vault_checked(
+    path: &State<StoragePath>,
+    vault: &Vault,
+    keys: &VaultKeys,
+    session_state: &State<SessionState>,
+) -> Result<(), CommandError> {
+    let mut journal_guard = session_state.journal.lock().unwrap();
+    let journal = storage::save_vault(path, vault, keys, journal_guard.as_ref())?;
+    *journal_guard = Some(journal);
     Ok(())
 }
 
+/// Compacts the change journal of the open vault, if it has any entries, then
+/// forgets the vault's contents and keys and releases its lock. A failed
+/// compaction is logged only; the journal keeps the changes either way.
+fn clear_session(
+    path: &State<StoragePath>,
+    vault_state: &mut Option<Vault>,
+    session_state: &State<SessionState>,
+) {
+    if let Some(vault) = vault_state.as_ref() {
+        let has_entries = session_state
+            .journal
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|journal| journal.entries() > 0);
+        let keys = session_state.vault_keys.lock().unwrap().clone();
+        if let (true, Some(keys)) = (has_entries, keys) {
+            if let Err(e) = save_vault_checked(path, vault, &keys, session_state) {
+                error!("Failed to compact the change journal: {}", e);
+            }
+        }
+    }
+    *vault_state = None;
+
+    // Dropping the keys zeroizes them and releases their locked memory.
+    *session_state.vault_keys.lock().unwrap() = None;
+    *session_state.vault_lock.lock().unwrap() = None;
+    *session_state.journal.lock().unwrap() = None;
+    session_state.undo.lock().unwrap().clear();
+    *session_state.pending_merge.lock().unwrap() = None;
+}
+
+/// Runs a command that derives keys on a blocking thread, so the KDF, which
+/// deliberately takes a while, doesn't hold up the async runtime. The command
+/// reads its state through `app`.
+async fn run_blocking<T: Send + 'static>(
+    app: AppHandle,
+    command: impl FnOnce(&AppHandle) -> Result<T, CommandError> + Send + 'static,
+) -> Result<T, CommandError> {
+    tauri::async_runtime::spawn_blocking(move || command(&app))
+        .await
+        .map_err(|e| CommandError::Interrupted(e.to_string()))?
+}
+
+fn read_optional_keyfile(keyfile_path: Option<String>) -> Result<Option<KeyfileHash>, CommandError> {
+    let keyfile = keyfile_path
+        .map(|keyfile_path| storage::read_keyfile(Path::new(&keyfile_path)))
+        .transpose()?;
+    Ok(keyfile)
+}
+
 // --- Vault Lifecycle Commands ---
 
 #[tauri::command]
@@ -73,102 +259,1214 @@ pub fn vault_exists(path: State<StoragePath>) -> bool {
     storage::vault_exists(&path)
 }
 
+#[tauri::command]
+pub fn vault_requires_keyfile(path: State<StoragePath>) -> Result<bool, CommandError> {
+    Ok(storage::vault_requires_keyfile(&path)?)
+}
+
 #[tauri::command]
 pub fn get_default_service_types_list() -> Vec<ServiceType> {
     defaults::get_default_service_types()
 }
 
+/// Benchmarks key derivation on this machine and suggests KDF parameters that
+/// take about `target_ms` to unlock the vault. Runs on a blocking thread since
+/// it deliberately takes a while.
+#[tauri::command]
+pub async fn calibrate_kdf(
+    target_ms: u64,
+    algorithm: Option<KdfAlgorithm>,
+) -> Result<KdfCalibration, CommandError> {
+    let algorithm = algorithm.unwrap_or(KdfAlgorithm::Argon2id);
+    let calibration = tauri::async_runtime::spawn_blocking(move || {
+        crypto::calibrate_kdf(algorithm, std::time::Duration::from_millis(target_ms))
+    })
+    .await
+    .map_err(|e| CommandError::Interrupted(e.to_string()))??;
+    Ok(calibration)
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateVaultResult {
+    pub vault: Vault,
+    /// Shown to the user once; it is not stored anywhere in plaintext.
+    pub recovery_key: Option<String>,
+}
 
 #[tauri::command]
-pub fn create_vault(
+pub async fn create_vault(
+    password: String,
+    settings: Settings,
+    selected_service_type_ids: Vec<String>,
+    encryption: Option<EncryptionSettings>,
+    keyfile_path: Option<String>,
+    with_recovery_key: Option<bool>,
+    app: AppHandle,
+) -> Result<CreateVaultResult, CommandError> {
+    run_blocking(app, move |app| {
+        create_vault_blocking(
+            app.state(),
+            password,
+            settings,
+            selected_service_type_ids,
+            encryption,
+            keyfile_path,
+            with_recovery_key,
+            app.state(),
+            app.state(),
+        )
+    })
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_vault_blocking(
     path: State<StoragePath>,
     password: String,
     settings: Settings,
     selected_service_type_ids: Vec<String>,
+    encryption: Option<EncryptionSettings>,
+    keyfile_path: Option<String>,
+    with_recovery_key: Option<bool>,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<Vault, CommandError> {
+) -> Result<CreateVaultResult, CommandError> {
     let mut vault_state = app_state.0.lock().unwrap();
     if vault_exists(path.clone()) {
         if let Some(existing_vault) = vault_state.as_ref() {
-            return Ok(existing_vault.clone());
+            return Ok(CreateVaultResult {
+                vault: existing_vault.clone(),
+                recovery_key: None,
+            });
         }
     }
 
     let all_default_types = defaults::get_default_service_types();
+    let now = Utc::now().to_rfc3339();
     let selected_types = all_default_types
         .into_iter()
         .filter(|st| selected_service_type_ids.contains(&st.id))
+        .map(|st| ServiceType {
+            timestamps: Timestamps::new(&now),
+            ..st
+        })
         .collect();
 
     let new_vault = Vault {
-        version: "0.2.0".to_string(),
+        version: migrations::CURRENT_VAULT_VERSION.to_string(),
         service_types: selected_types,
         services: vec![],
         accounts: vec![],
         settings,
+        trash: vec![],
+    };
+
+    let vault_lock = VaultLock::acquire(&path.get())?;
+    let master_pass = MasterPassword(password);
+    let keyfile = read_optional_keyfile(keyfile_path)?;
+    let encryption = encryption.unwrap_or_default();
+    let mut keys = VaultKeys::new(&master_pass, keyfile.as_ref(), &encryption)?;
+    let recovery_key = if with_recovery_key.unwrap_or(false) {
+        Some(keys.set_recovery_key()?)
+    } else {
+        None
+    };
+    let journal = storage::save_vault(&path, &new_vault, &keys, None)?;
+
+    *session_state.vault_keys.lock().unwrap() = Some(keys);
+    *session_state.journal.lock().unwrap() = Some(journal);
+    *session_state.vault_lock.lock().unwrap() = Some(vault_lock);
+
+    let vault_clone = new_vault.clone();
+    *vault_state = Some(new_vault);
+
+    Ok(CreateVaultResult {
+        vault: vault_clone,
+        recovery_key: recovery_key.map(|key| key.0.clone()),
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnlockResult {
+    pub vault: Vault,
+    /// The password slot was re-wrapped with the default KDF settings and saved.
+    pub kdf_upgraded: bool,
+}
+
+#[tauri::command]
+pub async fn unlock_vault(
+    password: String,
+    keyfile_path: Option<String>,
+    app: AppHandle,
+) -> Result<UnlockResult, CommandError> {
+    run_blocking(app, move |app| {
+        unlock_vault_blocking(
+            app.state(),
+            password,
+            keyfile_path,
+            app.state(),
+            app.state(),
+        )
+    })
+    .await
+}
+
+fn unlock_vault_blocking(
+    path: State<StoragePath>,
+    password: String,
+    keyfile_path: Option<String>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<UnlockResult, CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    if let Some(existing_vault) = vault_state.as_ref() {
+        return Ok(UnlockResult {
+            vault: existing_vault.clone(),
+            kdf_upgraded: false,
+        });
+    }
+
+    let vault_lock = VaultLock::acquire(&path.get())?;
+    let master_pass = MasterPassword(password);
+    let keyfile = read_optional_keyfile(keyfile_path)?;
+    let loaded = storage::load_vault(&path, &master_pass, keyfile.as_ref())?;
+    let mut keys = loaded.keys;
+    let mut journal = loaded.journal;
+    let mut kdf_upgraded = false;
+
+    if loaded.needs_upgrade || loaded.migrated {
+        let mut upgraded = keys.clone();
+        // Older envelopes already got a data key on load; the password slot
+        // only needs re-wrapping when its KDF is legacy or below the minimum.
+        let rewrapped = if loaded.kdf_needs_upgrade {
+            let encryption = EncryptionSettings {
+                kdf: KdfParams::default(),
+                ..upgraded.settings()
+            };
+            // Keep the keyfile only if the vault was actually encrypted with it.
+            let keyfile = keyfile.filter(|_| upgraded.uses_keyfile());
+            upgraded.rewrap_password(&master_pass, keyfile.as_ref(), &encryption)
+        } else {
+            Ok(())
+        };
+        // A failed upgrade must not prevent unlocking; the old file stays valid
+        // and a migrated vault is written on the next save. The exception is a
+        // data key generated on load: journal entries sealed with it before it
+        // reaches the disk could never be read back.
+        let result = rewrapped
+            .map_err(StorageError::from)
+            .and_then(|()| storage::save_vault(&path, &loaded.vault, &upgraded, Some(&journal)));
+        match result {
+            Ok(upgraded_journal) => {
+                info!("Vault saved in the current format.");
+                keys = upgraded;
+                journal = upgraded_journal;
+                kdf_upgraded = loaded.kdf_needs_upgrade;
+            }
+            Err(e) if loaded.new_data_key => {
+                error!("Failed to save the vault with its new data key: {}", e);
+                return Err(e.into());
+            }
+            Err(e) => error!("Failed to upgrade vault: {}", e),
+        }
+    }
+
+    let mut vault = loaded.vault;
+    let expired = expired_trash(&vault, Utc::now());
+    if !expired.is_empty() {
+        let count = expired.len();
+        let operation = Operation::PurgeTrash { items: expired };
+        // Like the upgrade above, this is retried on the next unlock if it fails.
+        match journal.append(&path.get(), &operation, &keys) {
+            Ok(_) => {
+                operation.apply(&mut vault)?;
+                info!("Purged {} expired items from the trash", count);
+            }
+            Err(e) => error!("Failed to purge the trash: {}", e),
+        }
+    }
+
+    *session_state.vault_keys.lock().unwrap() = Some(keys);
+    *session_state.vault_lock.lock().unwrap() = Some(vault_lock);
+    *session_state.journal.lock().unwrap() = Some(journal);
+
+    let vault_clone = vault.clone();
+    *vault_state = Some(vault);
+
+    Ok(UnlockResult {
+        vault: vault_clone,
+        kdf_upgraded,
+    })
+}
+
+/// Opens a locked vault with its recovery key and sets a new master password
+/// (and optionally a keyfile). The recovery key stays valid afterwards.
+#[tauri::command]
+pub async fn recover_vault(
+    recovery_key: String,
+    new_password: String,
+    keyfile_path: Option<String>,
+    app: AppHandle,
+) -> Result<Vault, CommandError> {
+    run_blocking(app, move |app| {
+        recover_vault_blocking(
+            app.state(),
+            recovery_key,
+            new_password,
+            keyfile_path,
+            app.state(),
+            app.state(),
+        )
+    })
+    .await
+}
+
+fn recover_vault_blocking(
+    path: State<StoragePath>,
+    recovery_key: String,
+    new_password: String,
+    keyfile_path: Option<String>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    recover_with_key(&path, &recovery_key, new_password, keyfile_path, &app_state, &session_state)
+}
+
+/// Like `recover_vault`, but the recovery key is rebuilt from recovery shares,
+/// given as text in `shares` or as files in `share_paths`.
+#[tauri::command]
+pub async fn recover_vault_with_shares(
+    shares: Vec<String>,
+    share_paths: Option<Vec<String>>,
+    new_password: String,
+    keyfile_path: Option<String>,
+    app: AppHandle,
+) -> Result<Vault, CommandError> {
+    run_blocking(app, move |app| {
+        recover_vault_with_shares_blocking(
+            app.state(),
+            shares,
+            share_paths,
+            new_password,
+            keyfile_path,
+            app.state(),
+            app.state(),
+        )
+    })
+    .await
+}
+
+fn recover_vault_with_shares_blocking(
+    path: State<StoragePath>,
+    shares: Vec<String>,
+    share_paths: Option<Vec<String>>,
+    new_password: String,
+    keyfile_path: Option<String>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let mut shares = shares;
+    for share_path in share_paths.unwrap_or_default() {
+        shares.push(storage::read_recovery_share(Path::new(&share_path))?);
+    }
+    let recovery_key = crypto::combine_recovery_shares(&shares)?;
+    recover_with_key(&path, &recovery_key.0, new_password, keyfile_path, &app_state, &session_state)
+}
+
+fn recover_with_key(
+    path: &State<StoragePath>,
+    recovery_key: &str,
+    new_password: String,
+    keyfile_path: Option<String>,
+    app_state: &State<AppState>,
+    session_state: &State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    if vault_state.is_some() {
+        return Err(CommandError::AlreadyUnlocked);
+    }
+
+    let vault_lock = VaultLock::acquire(&path.get())?;
+    let master_pass = MasterPassword(new_password);
+    let keyfile = read_optional_keyfile(keyfile_path)?;
+    let loaded =
+        storage::load_vault_with_recovery_key(path, recovery_key, &master_pass, keyfile.as_ref())?;
+    let journal =
+        storage::save_vault(path, &loaded.vault, &loaded.keys, Some(&loaded.journal))?;
+
+    *session_state.vault_keys.lock().unwrap() = Some(loaded.keys);
+    *session_state.vault_lock.lock().unwrap() = Some(vault_lock);
+    *session_state.journal.lock().unwrap() = Some(journal);
+
+    let vault_clone = loaded.vault.clone();
+    *vault_state = Some(loaded.vault);
+
+    Ok(vault_clone)
+}
+
+/// Generates a new recovery key for the open vault, replacing any previous one.
+/// The vault is re-encrypted under a new data key, so an earlier recovery key
+/// no longer opens it. Backups made before still open with the earlier key,
+/// and the history can't reach back past this point.
+#[tauri::command]
+pub fn generate_recovery_key(
+    path: State<StoragePath>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<String, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let mut keys_guard = session_state.vault_keys.lock().unwrap();
+    let mut keys = keys_guard.clone().ok_or(CommandError::VaultLocked)?;
+    let recovery_key = keys.set_recovery_key()?;
+
+    // Only switch to the new keys once they are safely on disk.
+    save_vault_checked(&path, vault, &keys, &session_state)?;
+    *keys_guard = Some(keys);
+
+    Ok(recovery_key.0.clone())
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryShares {
+    pub threshold: u8,
+    /// Shown to the user once; like the recovery key, they are not stored.
+    pub shares: Vec<String>,
+    /// The files the shares were saved to, if an output directory was given.
+    pub files: Vec<String>,
+}
+
+/// Replaces the vault's recovery key with one split into `share_count` shares,
+/// any `threshold` of which can recover the vault. With `output_dir`, each
+/// share is also saved to its own file there. Like `generate_recovery_key`,
+/// this re-encrypts the vault under a new data key.
+#[tauri::command]
+pub fn generate_recovery_shares(
+    path: State<StoragePath>,
+    threshold: u8,
+    share_count: u8,
+    output_dir: Option<String>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<RecoveryShares, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let mut keys_guard = session_state.vault_keys.lock().unwrap();
+    let mut keys = keys_guard.clone().ok_or(CommandError::VaultLocked)?;
+    let shares = keys.set_recovery_shares(threshold, share_count)?;
+
+    // Write the share files before the vault, so a failure here leaves the
+    // previous recovery key in place.
+    let files = match output_dir {
+        Some(output_dir) => storage::write_recovery_shares(Path::new(&output_dir), &shares)?,
+        None => Vec::new(),
+    };
+    save_vault_checked(&path, vault, &keys, &session_state)?;
+    *keys_guard = Some(keys);
+
+    Ok(RecoveryShares {
+        threshold,
+        shares: shares.iter().map(|share| share.0.clone()).collect(),
+        files: files.iter().map(|file| file.display().to_string()).collect(),
+    })
+}
+
+#[tauri::command]
+pub fn list_backups(path: State<StoragePath>) -> Result<Vec<BackupInfo>, CommandError> {
+    Ok(backups::list_backups(&path.get())?)
+}
+
+/// Replaces the vault file with a backup. The vault is locked afterwards, as
+/// the backup may have been saved with a different password.
+#[tauri::command]
+pub fn restore_backup(
+    path: State<StoragePath>,
+    file_name: String,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<(), CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    let mut lock_guard = session_state.vault_lock.lock().unwrap();
+    // Keep another instance from saving over the restored file.
+    let _vault_lock = match lock_guard.take() {
+        Some(vault_lock) => vault_lock,
+        None => VaultLock::acquire(&path.get())?,
+    };
+    backups::restore_backup(&path.get(), &file_name)?;
+
+    *vault_state = None;
+    *session_state.vault_keys.lock().unwrap() = None;
+    *session_state.journal.lock().unwrap() = None;
+    session_state.undo.lock().unwrap().clear();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn lock_vault(
+    path: State<StoragePath>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<(), CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    if vault_state.is_none() {
+        return Err(CommandError::VaultLocked);
+    }
+    clear_session(&path, &mut vault_state, &session_state);
+
+    Ok(())
+}
+
+/// Clears a vault lock left behind by an instance on another machine, e.g.
+/// one that crashed while the vault was shared through a sync client.
+#[tauri::command]
+pub fn break_vault_lock(path: State<StoragePath>) -> Result<(), CommandError> {
+    Ok(vault_lock::break_lock(&path.get())?)
+}
+
+/// Reads the vault file again after it was changed outside AccMan, replacing
+/// the open vault. Changes that failed to save are discarded.
+#[tauri::command]
+pub fn reload_vault(
+    path: State<StoragePath>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    if vault_state.is_none() {
+        return Err(CommandError::VaultLocked);
+    }
+
+    let mut keys_guard = session_state.vault_keys.lock().unwrap();
+    let keys = keys_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    let loaded = storage::reload_vault(&path, keys)?;
+
+    *keys_guard = Some(loaded.keys);
+    *session_state.journal.lock().unwrap() = Some(loaded.journal);
+    session_state.undo.lock().unwrap().clear();
+
+    let vault_clone = loaded.vault.clone();
+    *vault_state = Some(loaded.vault);
+
+    Ok(vault_clone)
+}
+
+#[tauri::command]
+pub fn get_vault(app_state: State<AppState>) -> Result<Vault, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    match vault_guard.as_ref() {
+        Some(vault) => Ok(vault.clone()),
+        None => Err(CommandError::VaultLocked),
+    }
+}
+
+// --- Export / Import Commands ---
+
+/// Writes an encrypted copy of the open vault to `file_path`. Without an
+/// export password it is protected by the master password and keyfile.
+#[tauri::command]
+pub async fn export_vault_backup(
+    file_path: String,
+    export_password: Option<String>,
+    app: AppHandle,
+) -> Result<(), CommandError> {
+    run_blocking(app, move |app| {
+        export_vault_backup_blocking(file_path, export_password, app.state(), app.state())
+    })
+    .await
+}
+
+fn export_vault_backup_blocking(
+    file_path: String,
+    export_password: Option<String>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<(), CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let keys_guard = session_state.vault_keys.lock().unwrap();
+    let session_keys = keys_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    let export_password = export_password.filter(|password| !password.is_empty());
+    let keys = match export_password {
+        // A fresh data key, so the export shares no key material with the vault.
+        Some(password) => VaultKeys::new(&MasterPassword(password), None, &session_keys.settings())?,
+        None => session_keys.clone(),
+    };
+
+    storage::export_vault(Path::new(&file_path), vault, &keys)?;
+    Ok(())
+}
+
+#[derive(Debug, serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportMode {
+    /// The file's contents replace the open vault, settings included.
+    Replace,
+    /// Records from the file are added to the open vault. Where both have a
+    /// record, the open vault's version is kept.
+    Merge,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportResult {
+    pub vault: Vault,
+    #[serde(flatten)]
+    pub counts: ImportCounts,
+}
+
+/// Imports an exported vault into the open one. `password` is the export
+/// password, or the master password the vault had when it was exported. The
+/// result is saved under the open vault's own password.
+#[tauri::command]
+pub async fn import_vault_backup(
+    file_path: String,
+    password: String,
+    keyfile_path: Option<String>,
+    mode: ImportMode,
+    app: AppHandle,
+) -> Result<ImportResult, CommandError> {
+    run_blocking(app, move |app| {
+        import_vault_backup_blocking(
+            app.state(),
+            file_path,
+            password,
+            keyfile_path,
+            mode,
+            app.state(),
+            app.state(),
+        )
+    })
+    .await
+}
+
+fn import_vault_backup_blocking(
+    path: State<StoragePath>,
+    file_path: String,
+    password: String,
+    keyfile_path: Option<String>,
+    mode: ImportMode,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<ImportResult, CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    let vault = vault_state.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let keyfile = read_optional_keyfile(keyfile_path)?;
+    let imported =
+        storage::read_exported_vault(Path::new(&file_path), &MasterPassword(password), keyfile.as_ref())?;
+
+    let (new_vault, counts) = match mode {
+        ImportMode::Replace => {
+            let counts = ImportCounts::of(&imported);
+            (imported, counts)
+        }
+        ImportMode::Merge => {
+            let mut merged = vault.clone();
+            let counts = merge::merge_vaults(&mut merged, imported, &[]);
+            (merged, counts)
+        }
+    };
+
+    let keys_guard = session_state.vault_keys.lock().unwrap();
+    let keys = keys_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    save_vault_checked(&path, &new_vault, keys, &session_state)?;
+
+    info!(
+        "Imported {} service types, {} services and {} accounts ({} skipped).",
+        counts.service_types_added, counts.services_added, counts.accounts_added, counts.skipped
+    );
+    session_state.undo.lock().unwrap().clear();
+    *vault_state = Some(new_vault.clone());
+    Ok(ImportResult {
+        vault: new_vault,
+        counts,
+    })
+}
+
+// --- Merge Commands ---
+
+/// Decrypts another vault file with its own password and compares it with
+/// the open vault. The decrypted vault is kept until the merge is applied or
+/// cancelled.
+#[tauri::command]
+pub async fn preview_vault_merge(
+    file_path: String,
+    password: String,
+    keyfile_path: Option<String>,
+    app: AppHandle,
+) -> Result<MergePreview, CommandError> {
+    run_blocking(app, move |app| {
+        preview_vault_merge_blocking(file_path, password, keyfile_path, app.state(), app.state())
+    })
+    .await
+}
+
+fn preview_vault_merge_blocking(
+    file_path: String,
+    password: String,
+    keyfile_path: Option<String>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<MergePreview, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let keyfile = read_optional_keyfile(keyfile_path)?;
+    let theirs =
+        storage::read_exported_vault(Path::new(&file_path), &MasterPassword(password), keyfile.as_ref())?;
+    let preview = merge::preview_merge(vault, &theirs);
+    *session_state.pending_merge.lock().unwrap() = Some(theirs);
+    Ok(preview)
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeResult {
+    pub vault: Vault,
+    #[serde(flatten)]
+    pub counts: ImportCounts,
+}
+
+/// Merges the vault from `preview_vault_merge` into the open one, resolving
+/// conflicts as decided. Conflicts without a decision keep the open vault's record.
+#[tauri::command]
+pub fn apply_vault_merge(
+    path: State<StoragePath>,
+    decisions: Vec<MergeDecision>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<MergeResult, CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    let vault = vault_state.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let mut pending_guard = session_state.pending_merge.lock().unwrap();
+    let theirs = pending_guard.clone().ok_or(CommandError::NoPendingMerge)?;
+    let mut merged = vault.clone();
+    let counts = merge::merge_vaults(&mut merged, theirs, &decisions);
+
+    let keys_guard = session_state.vault_keys.lock().unwrap();
+    let keys = keys_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    save_vault_checked(&path, &merged, keys, &session_state)?;
+
+    info!(
+        "Merged {} service types, {} services and {} accounts ({} replaced, {} skipped).",
+        counts.service_types_added, counts.services_added, counts.accounts_added, counts.replaced, counts.skipped
+    );
+    *pending_guard = None;
+    session_state.undo.lock().unwrap().clear();
+    *vault_state = Some(merged.clone());
+    Ok(MergeResult {
+        vault: merged,
+        counts,
+    })
+}
+
+#[tauri::command]
+pub fn cancel_vault_merge(session_state: State<SessionState>) {
+    *session_state.pending_merge.lock().unwrap() = None;
+}
+
+// --- History Commands ---
+
+fn parse_history_time(time: &str) -> Result<DateTime<Utc>, CommandError> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| CommandError::InvalidHistoryTime(time.to_string()))
+}
+
+/// Rebuilds the open vault as it was at `time`.
+fn vault_at(
+    path: &State<StoragePath>,
+    time: &str,
+    session_state: &State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let time = parse_history_time(time)?;
+    let keys_guard = session_state.vault_keys.lock().unwrap();
+    let keys = keys_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    Ok(history::vault_at(&path.get(), time, keys)?)
+}
+
+/// Lists the recorded changes to the open vault, newest first.
+#[tauri::command]
+pub fn list_vault_history(
+    path: State<StoragePath>,
+    session_state: State<SessionState>,
+) -> Result<Vec<JournalEntry>, CommandError> {
+    let keys_guard = session_state.vault_keys.lock().unwrap();
+    let keys = keys_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    Ok(history::list_changes(&path.get(), keys)?)
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPreview {
+    /// The vault as it was at the requested time.
+    pub vault: Vault,
+    /// How the open vault differs from it.
+    pub changes: Vec<RecordChange>,
+}
+
+/// Shows the vault as it was at `time`, an RFC 3339 timestamp, and what has
+/// changed since. Nothing is restored.
+#[tauri::command]
+pub fn preview_vault_at(
+    path: State<StoragePath>,
+    time: String,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<HistoryPreview, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+
+    let earlier = vault_at(&path, &time, &session_state)?;
+    let changes = history::compare(&earlier, vault);
+    Ok(HistoryPreview {
+        vault: earlier,
+        changes,
+    })
+}
+
+/// Puts one record back the way it was at `time`. A record added since is
+/// moved to the trash. The restore is recorded like any other change.
+#[tauri::command]
+pub fn restore_history_record(
+    path: State<StoragePath>,
+    time: String,
+    kind: RecordKind,
+    id: String,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let earlier = vault_at(&path, &time, &session_state)?;
+    let operation = {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        history::restore_record(&earlier, vault, kind, &id, &Utc::now().to_rfc3339())
+    };
+
+    match operation {
+        Some(operation) => apply_operation(&path, operation, &app_state, &session_state)?,
+        None => info!("Record {} is unchanged since {}", id, time),
+    }
+    get_vault(app_state)
+}
+
+/// Replaces the whole vault with the way it was at `time`. With backups on,
+/// the current state is backed up first like before any full save, so this
+/// can be undone.
+#[tauri::command]
+pub fn restore_vault_at(
+    path: State<StoragePath>,
+    time: String,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let mut vault_state = app_state.0.lock().unwrap();
+    if vault_state.is_none() {
+        return Err(CommandError::VaultLocked);
+    }
+
+    let restored = vault_at(&path, &time, &session_state)?;
+    let keys = session_state.vault_keys.lock().unwrap().clone().ok_or(CommandError::VaultLocked)?;
+    save_vault_checked(&path, &restored, &keys, &session_state)?;
+    info!("Vault restored to its state at {}", time);
+    session_state.undo.lock().unwrap().clear();
+
+    let vault_clone = restored.clone();
+    *vault_state = Some(restored);
+    Ok(vault_clone)
+}
+
+// --- Record Queries ---
+
+#[tauri::command]
+pub fn list_accounts(
+    query: Option<RecordQuery>,
+    app_state: State<AppState>,
+) -> Result<Vec<Account>, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    Ok(query::query_records(&vault.accounts, &query.unwrap_or_default(), Utc::now()))
+}
+
+#[tauri::command]
+pub fn list_services(
+    query: Option<RecordQuery>,
+    app_state: State<AppState>,
+) -> Result<Vec<Service>, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    Ok(query::query_records(&vault.services, &query.unwrap_or_default(), Utc::now()))
+}
+
+#[tauri::command]
+pub fn list_service_types(
+    query: Option<RecordQuery>,
+    app_state: State<AppState>,
+) -> Result<Vec<ServiceType>, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    Ok(query::query_records(&vault.service_types, &query.unwrap_or_default(), Utc::now()))
+}
+
+/// Records that a record was just used, e.g. one of its secrets copied, and
+/// returns the time recorded. This is journaled but not a change that can be
+/// undone.
+#[tauri::command]
+pub fn mark_record_used(
+    path: State<StoragePath>,
+    kind: RecordKind,
+    id: String,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<String, CommandError> {
+    let mut vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
+    let used_at = Utc::now().to_rfc3339();
+    let operation = Operation::SetLastUsed {
+        kind,
+        id,
+        last_used_at: Some(used_at.clone()),
+    };
+    journal_operation(&path, &operation, vault, &session_state)?;
+    Ok(used_at)
+}
+
+// --- Undo Commands ---
+
+/// Undoes the last `steps` changes, one if not given, or fewer if there are
+/// not that many. The undo is journaled like any other change.
+#[tauri::command]
+pub fn undo(
+    path: State<StoragePath>,
+    steps: Option<usize>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    replay_steps(&path, steps, false, &app_state, &session_state)
+}
+
+/// Makes the last `steps` undone changes again, one if not given. Making any
+/// other change first discards them.
+#[tauri::command]
+pub fn redo(
+    path: State<StoragePath>,
+    steps: Option<usize>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    replay_steps(&path, steps, true, &app_state, &session_state)
+}
+
+#[tauri::command]
+pub fn get_undo_state(session_state: State<SessionState>) -> UndoState {
+    session_state.undo.lock().unwrap().state()
+}
+
+/// Undoes or redoes up to `steps` changes. If one fails, the ones before it
+/// stay undone or redone.
+fn replay_steps(
+    path: &State<StoragePath>,
+    steps: Option<usize>,
+    redo: bool,
+    app_state: &State<AppState>,
+    session_state: &State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let mut vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
+    let mut history = session_state.undo.lock().unwrap();
+
+    let state = history.state();
+    match (redo, state.undo_steps, state.redo_steps) {
+        (false, 0, _) => return Err(CommandError::NothingToUndo),
+        (true, _, 0) => return Err(CommandError::NothingToRedo),
+        _ => {}
+    }
+
+    for _ in 0..steps.unwrap_or(1) {
+        let step = if redo { history.pop_redo() } else { history.pop_undo() };
+        let Some(step) = step else { break };
+        let operation = if redo { &step.operation } else { &step.inverse };
+        let operation = operation.keeping_last_used(vault);
+        if let Err(e) = journal_operation(path, &operation, vault, session_state) {
+            if redo {
+                history.push_redo(step);
+            } else {
+                history.push_undo(step);
+            }
+            return Err(e);
+        }
+        if redo {
+            history.push_undo(step);
+        } else {
+            history.push_redo(step);
+        }
+    }
+    Ok(vault.clone())
+}
+
+// --- Trash Commands ---
+
+/// Trash items deleted longer ago than the vault's retention period allows.
+fn expired_trash(vault: &Vault, now: DateTime<Utc>) -> Vec<TrashKey> {
+    let days = vault.settings.trash_retention_days;
+    if days == 0 {
+        return Vec::new();
+    }
+    let cutoff = now - chrono::Duration::days(i64::from(days));
+    vault
+        .trash
+        .iter()
+        .filter(|item| journal::parse_time(&item.deleted_at).is_some_and(|deleted_at| deleted_at < cutoff))
+        .map(TrashKey::of)
+        .collect()
+}
+
+#[tauri::command]
+pub fn list_trash(app_state: State<AppState>) -> Result<Vec<TrashedItem>, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    Ok(vault.trash.clone())
+}
+
+/// Puts a record back from the trash. A restored service is linked again to
+/// the accounts it was linked to, if they still exist.
+#[tauri::command]
+pub fn restore_from_trash(
+    path: State<StoragePath>,
+    kind: RecordKind,
+    id: String,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<Vault, CommandError> {
+    let operation = Operation::RestoreFromTrash {
+        item: TrashKey { kind, id },
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    get_vault(app_state)
+}
+
+/// Deletes the given trash items for good.
+#[tauri::command]
+pub fn purge_trash(
+    path: State<StoragePath>,
+    items: Vec<TrashKey>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<(), CommandError> {
+    let operation = Operation::PurgeTrash { items };
+    apply_operation(&path, operation, &app_state, &session_state)
+}
+
+#[tauri::command]
+pub fn empty_trash(
+    path: State<StoragePath>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<(), CommandError> {
+    let items = {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        vault.trash.iter().map(TrashKey::of).collect()
     };
+    let operation = Operation::PurgeTrash { items };
+    apply_operation(&path, operation, &app_state, &session_state)
+}
 
-    let master_pass = MasterPassword(password);
-    storage::save_vault(&path, &new_vault, &master_pass)?;
+// --- Vault Registry Commands ---
 
-    let mut session_pass = session_state.master_password.lock().unwrap();
-    *session_pass = Some(master_pass);
+/// Makes `id` the active vault: the open vault is locked and later commands
+/// use the file of the new one.
+fn activate_vault(
+    id: &str,
+    registry: &mut VaultRegistry,
+    registry_state: &State<RegistryState>,
+    path: &State<StoragePath>,
+    app_state: &State<AppState>,
+    session_state: &State<SessionState>,
+) -> Result<VaultProfile, CommandError> {
+    let profile = registry
+        .find(id)
+        .cloned()
+        .ok_or_else(|| CommandError::VaultNotRegistered(id.to_string()))?;
 
-    let vault_clone = new_vault.clone();
-    *vault_state = Some(new_vault);
+    let mut vault_state = app_state.0.lock().unwrap();
+    let previous_id = registry.active_vault_id.replace(profile.id.clone());
+    if let Err(e) = registry.save(&registry_state.app_data_dir) {
+        registry.active_vault_id = previous_id;
+        return Err(e.into());
+    }
 
-    Ok(vault_clone)
+    clear_session(path, &mut vault_state, session_state);
+    path.set(profile.path.clone());
+    info!("Switched to vault '{}' at {}", profile.name, profile.path.display());
+    Ok(profile)
+}
+
+fn validate_vault_name(name: String) -> Result<String, CommandError> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(CommandError::EmptyVaultName);
+    }
+    Ok(name)
+}
+
+#[tauri::command]
+pub fn list_vaults(registry_state: State<RegistryState>) -> VaultRegistry {
+    registry_state.registry.lock().unwrap().clone()
+}
+
+/// Whether the app keeps its data next to the executable instead of in the
+/// user's app data folder.
+#[tauri::command]
+pub fn is_portable_mode(registry_state: State<RegistryState>) -> bool {
+    registry_state.portable
 }
 
+/// Registers a new vault at `vault_path`, or in the app data folder without
+/// one, and switches to it. The vault itself is then set up with `create_vault`.
 #[tauri::command]
-pub fn unlock_vault(
+pub fn create_vault_profile(
+    name: String,
+    vault_path: Option<String>,
+    registry_state: State<RegistryState>,
     path: State<StoragePath>,
-    password: String,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<Vault, CommandError> {
-    let mut vault_state = app_state.0.lock().unwrap();
-    if let Some(existing_vault) = vault_state.as_ref() {
-        return Ok(existing_vault.clone());
+) -> Result<VaultProfile, CommandError> {
+    let name = validate_vault_name(name)?;
+    let vault_path = match vault_path {
+        Some(vault_path) => PathBuf::from(vault_path),
+        None => registry::default_vault_path(&registry_state.app_data_dir),
+    };
+    if vault_path.exists() {
+        return Err(CommandError::VaultFileExists(vault_path.display().to_string()));
+    }
+    if let Some(parent) = vault_path.parent() {
+        std::fs::create_dir_all(parent).map_err(StorageError::from)?;
     }
 
-    let master_pass = MasterPassword(password);
-    let vault = storage::load_vault(&path, &master_pass)?;
-
-    let mut session_pass = session_state.master_password.lock().unwrap();
-    *session_pass = Some(master_pass);
-
-    let vault_clone = vault.clone();
-    *vault_state = Some(vault);
+    let mut registry = registry_state.registry.lock().unwrap();
+    let profile = registry.add(name, vault_path);
+    let result = activate_vault(&profile.id, &mut registry, &registry_state, &path, &app_state, &session_state);
+    if result.is_err() {
+        registry.remove(&profile.id);
+    }
+    result
+}
 
-    Ok(vault_clone)
+/// Registers an existing vault file and switches to it. A file that is
+/// registered already is just switched to.
+#[tauri::command]
+pub fn open_vault_profile(
+    name: String,
+    vault_path: String,
+    registry_state: State<RegistryState>,
+    path: State<StoragePath>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<VaultProfile, CommandError> {
+    let name = validate_vault_name(name)?;
+    open_vault_at(name, PathBuf::from(vault_path), &registry_state, &path, &app_state, &session_state)
 }
 
+/// Opens the vault file at `vault_path`, e.g. on a USB drive or network
+/// share. It is registered under its file name unless it is known already.
 #[tauri::command]
-pub fn lock_vault(
+pub fn open_vault_file(
+    vault_path: String,
+    registry_state: State<RegistryState>,
+    path: State<StoragePath>,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_state = app_state.0.lock().unwrap();
-    if vault_state.is_none() {
-        return Err(CommandError::VaultLocked);
+) -> Result<VaultProfile, CommandError> {
+    let vault_path = PathBuf::from(vault_path);
+    let name = vault_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = validate_vault_name(name)?;
+    open_vault_at(name, vault_path, &registry_state, &path, &app_state, &session_state)
+}
+
+fn open_vault_at(
+    name: String,
+    vault_path: PathBuf,
+    registry_state: &State<RegistryState>,
+    path: &State<StoragePath>,
+    app_state: &State<AppState>,
+    session_state: &State<SessionState>,
+) -> Result<VaultProfile, CommandError> {
+    if !vault_path.is_file() {
+        return Err(StorageError::NotFound(vault_path.display().to_string()).into());
     }
-    *vault_state = None;
 
-    let mut session_pass = session_state.master_password.lock().unwrap();
-    *session_pass = None; // This will trigger ZeroizeOnDrop for MasterPassword
+    let mut registry = registry_state.registry.lock().unwrap();
+    if let Some(profile) = registry.find_by_path(&vault_path).cloned() {
+        return activate_vault(&profile.id, &mut registry, registry_state, path, app_state, session_state);
+    }
+    let profile = registry.add(name, vault_path);
+    let result = activate_vault(&profile.id, &mut registry, registry_state, path, app_state, session_state);
+    if result.is_err() {
+        registry.remove(&profile.id);
+    }
+    result
+}
 
-    Ok(())
+/// Locks the open vault and makes another registered vault the active one.
+#[tauri::command]
+pub fn switch_vault(
+    id: String,
+    registry_state: State<RegistryState>,
+    path: State<StoragePath>,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<VaultProfile, CommandError> {
+    let mut registry = registry_state.registry.lock().unwrap();
+    activate_vault(&id, &mut registry, &registry_state, &path, &app_state, &session_state)
 }
 
 #[tauri::command]
-pub fn get_vault(app_state: State<AppState>) -> Result<Vault, CommandError> {
-    let vault_guard = app_state.0.lock().unwrap();
-    match vault_guard.as_ref() {
-        Some(vault) => Ok(vault.clone()),
-        None => Err(CommandError::VaultLocked),
+pub fn rename_vault(
+    id: String,
+    name: String,
+    registry_state: State<RegistryState>,
+) -> Result<VaultProfile, CommandError> {
+    let name = validate_vault_name(name)?;
+    let mut registry = registry_state.registry.lock().unwrap();
+    let mut renamed = registry.clone();
+    let profile = renamed
+        .find_mut(&id)
+        .ok_or_else(|| CommandError::VaultNotRegistered(id.clone()))?;
+    profile.name = name;
+    let profile = profile.clone();
+
+    renamed.save(&registry_state.app_data_dir)?;
+    *registry = renamed;
+    Ok(profile)
+}
+
+/// Removes a vault from the registry. Its file and backups stay on disk.
+#[tauri::command]
+pub fn forget_vault(id: String, registry_state: State<RegistryState>) -> Result<(), CommandError> {
+    let mut registry = registry_state.registry.lock().unwrap();
+    if registry.active_vault_id.as_deref() == Some(id.as_str()) {
+        return Err(CommandError::ActiveVaultCannotBeForgotten);
     }
+    let mut remaining = registry.clone();
+    remaining
+        .remove(&id)
+        .ok_or_else(|| CommandError::VaultNotRegistered(id.clone()))?;
+
+    remaining.save(&registry_state.app_data_dir)?;
+    *registry = remaining;
+    Ok(())
 }
 
 // --- Settings Commands ---
@@ -180,15 +1478,8 @@ pub fn update_settings(
     app_state: State<AppState>,
     session_state: State<SessionState>,
 ) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    vault.settings = settings;
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+    let operation = Operation::UpdateSettings { settings };
+    apply_operation(&path, operation, &app_state, &session_state)
 }
 
 #[tauri::command]
@@ -198,20 +1489,11 @@ pub fn delete_services(
     app_state: State<'_, AppState>,
     session_state: State<'_, SessionState>,
 ) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    vault.services.retain(|s| !service_ids.contains(&s.id));
-
-    // Also remove these services from any account that links to them
-    for account in &mut vault.accounts {
-        account.linked_services.retain(|id| !service_ids.contains(id));
-    }
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+    let operation = Operation::TrashServices {
+        service_ids,
+        deleted_at: Utc::now().to_rfc3339(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)
 }
 
 
@@ -223,24 +1505,16 @@ pub fn add_service_type(
     service_type: ServiceType,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    if vault
-        .service_types
-        .iter()
-        .any(|st| st.id == service_type.id)
-    {
-        return Err(CommandError::ServiceTypeExists(service_type.id));
-    }
-
-    vault.service_types.push(service_type);
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+) -> Result<ServiceType, CommandError> {
+    let service_type = ServiceType {
+        timestamps: Timestamps::new(&Utc::now().to_rfc3339()),
+        ..service_type
+    };
+    let operation = Operation::AddServiceType {
+        service_type: service_type.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(service_type)
 }
 
 #[tauri::command]
@@ -249,24 +1523,26 @@ pub fn update_service_type(
     service_type: ServiceType,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    if let Some(st) = vault
-        .service_types
-        .iter_mut()
-        .find(|st| st.id == service_type.id)
-    {
-        *st = service_type;
-    } else {
-        return Err(CommandError::ServiceTypeNotFound(service_type.id));
-    }
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
+) -> Result<ServiceType, CommandError> {
+    let service_type = {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        let existing = vault
+            .service_types
+            .iter()
+            .find(|st| st.id == service_type.id)
+            .ok_or_else(|| CommandError::ServiceTypeNotFound(service_type.id.clone()))?;
+        ServiceType {
+            timestamps: existing.timestamps.updated(&Utc::now().to_rfc3339()),
+            ..service_type
+        }
+    };
 
-    Ok(())
+    let operation = Operation::UpdateServiceType {
+        service_type: service_type.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(service_type)
 }
 
 #[tauri::command]
@@ -276,22 +1552,11 @@ pub fn delete_service_type(
     app_state: State<AppState>,
     session_state: State<SessionState>,
 ) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    let initial_len = vault.service_types.len();
-    vault
-        .service_types
-        .retain(|st| st.id != service_type_id);
-
-    if vault.service_types.len() == initial_len {
-        return Err(CommandError::ServiceTypeNotFound(service_type_id));
-    }
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+    let operation = Operation::TrashServiceType {
+        service_type_id,
+        deleted_at: Utc::now().to_rfc3339(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)
 }
 
 // --- Service Commands ---
@@ -303,28 +1568,17 @@ pub fn add_service(
     account_id: Option<String>,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    let service_id = service.id.clone();
-    vault.services.push(service);
-
-    // If account_id is provided, link the service to the account
-    if let Some(account_id) = account_id {
-        if let Some(account) = vault.accounts.iter_mut().find(|a| a.id == account_id) {
-            account.linked_services.push(service_id);
-            account.linked_services.sort();
-            account.linked_services.dedup();
-        } else {
-            return Err(CommandError::AccountNotFound(account_id));
-        }
-    }
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+) -> Result<Service, CommandError> {
+    let service = Service {
+        timestamps: Timestamps::new(&Utc::now().to_rfc3339()),
+        ..service
+    };
+    let operation = Operation::AddService {
+        service: service.clone(),
+        account_id,
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(service)
 }
 
 #[tauri::command]
@@ -333,61 +1587,121 @@ pub fn add_services(
     services: Vec<Service>,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    vault.services.extend(services);
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+) -> Result<Vec<Service>, CommandError> {
+    let now = Utc::now().to_rfc3339();
+    let services: Vec<Service> = services
+        .into_iter()
+        .map(|service| Service {
+            timestamps: Timestamps::new(&now),
+            ..service
+        })
+        .collect();
+    let operation = Operation::AddServices {
+        services: services.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(services)
 }
 
+/// Replaces a service. The values it replaces are added to the service's
+/// field history. Returns the service as saved, with that history.
 #[tauri::command]
 pub fn update_service(
     path: State<StoragePath>,
     service: Service,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
+) -> Result<Service, CommandError> {
+    let service = {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        let existing = find_service(vault, &service.id)?;
+        let now = Utc::now().to_rfc3339();
+        let service = Service {
+            timestamps: existing.timestamps.updated(&now),
+            ..service
+        };
+        service.with_history_from(existing, &now)
+    };
 
-    if let Some(s) = vault.services.iter_mut().find(|s| s.id == service.id) {
-        *s = service;
-    } else {
-        return Err(CommandError::ServiceNotFound(service.id));
-    }
+    let operation = Operation::UpdateService {
+        service: service.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(service)
+}
 
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
+fn find_service<'a>(vault: &'a Vault, service_id: &str) -> Result<&'a Service, CommandError> {
+    vault
+        .services
+        .iter()
+        .find(|s| s.id == service_id)
+        .ok_or_else(|| CommandError::ServiceNotFound(service_id.to_string()))
+}
 
-    Ok(())
+/// Earlier values of each of the service's fields, oldest first.
+#[tauri::command]
+pub fn get_service_field_history(
+    service_id: String,
+    app_state: State<AppState>,
+) -> Result<std::collections::HashMap<String, Vec<FieldValue>>, CommandError> {
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    Ok(find_service(vault, &service_id)?.field_history.clone())
 }
 
+/// Sets a field back to the value it had until `replaced_at`. The current
+/// value goes into the field history like with any other change.
 #[tauri::command]
-pub fn delete_service(
+pub fn restore_field_value(
     path: State<StoragePath>,
     service_id: String,
+    field_key: String,
+    replaced_at: String,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
+) -> Result<Service, CommandError> {
+    let service = {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        let existing = find_service(vault, &service_id)?;
+        let earlier = existing
+            .field_history
+            .get(&field_key)
+            .and_then(|values| values.iter().find(|value| value.replaced_at == replaced_at))
+            .ok_or_else(|| CommandError::FieldValueNotFound(field_key.clone()))?;
+
+        let now = Utc::now().to_rfc3339();
+        let mut service = existing.clone();
+        service.data.insert(field_key, earlier.value.clone());
+        service.timestamps = existing.timestamps.updated(&now);
+        service.with_history_from(existing, &now)
+    };
 
-    let initial_len = vault.services.len();
-    vault.services.retain(|s| s.id != service_id);
+    let operation = Operation::UpdateService {
+        service: service.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(service)
+}
 
-    if vault.services.len() == initial_len {
-        return Err(CommandError::ServiceNotFound(service_id));
+#[tauri::command]
+pub fn delete_service(
+    path: State<StoragePath>,
+    service_id: String,
+    app_state: State<AppState>,
+    session_state: State<SessionState>,
+) -> Result<(), CommandError> {
+    {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        find_service(vault, &service_id)?;
     }
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+    let operation = Operation::TrashServices {
+        service_ids: vec![service_id],
+        deleted_at: Utc::now().to_rfc3339(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)
 }
 
 // --- Account Commands ---
@@ -398,16 +1712,16 @@ pub fn add_account(
     account: Account,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    vault.accounts.push(account);
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+) -> Result<Account, CommandError> {
+    let account = Account {
+        timestamps: Timestamps::new(&Utc::now().to_rfc3339()),
+        ..account
+    };
+    let operation = Operation::AddAccount {
+        account: account.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(account)
 }
 
 #[tauri::command]
@@ -416,20 +1730,30 @@ pub fn update_account(
     account: Account,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    if let Some(a) = vault.accounts.iter_mut().find(|a| a.id == account.id) {
-        *a = account;
-    } else {
-        return Err(CommandError::AccountNotFound(account.id));
-    }
+) -> Result<Account, CommandError> {
+    let account = {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        let existing = find_account(vault, &account.id)?;
+        Account {
+            timestamps: existing.timestamps.updated(&Utc::now().to_rfc3339()),
+            ..account
+        }
+    };
 
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
+    let operation = Operation::UpdateAccount {
+        account: account.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(account)
+}
 
-    Ok(())
+fn find_account<'a>(vault: &'a Vault, account_id: &str) -> Result<&'a Account, CommandError> {
+    vault
+        .accounts
+        .iter()
+        .find(|a| a.id == account_id)
+        .ok_or_else(|| CommandError::AccountNotFound(account_id.to_string()))
 }
 
 #[tauri::command]
@@ -439,24 +1763,11 @@ pub fn delete_account(
     app_state: State<AppState>,
     session_state: State<SessionState>,
 ) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    let initial_len = vault.accounts.len();
-    vault.accounts.retain(|a| a.id != account_id);
-
-    if vault.accounts.len() == initial_len {
-        return Err(CommandError::AccountNotFound(account_id));
-    }
-
-    // Also unlink services from this account
-    // This is a simple implementation. A more robust one might check if other accounts use these services.
-    // For now, we assume services can be linked to multiple accounts.
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-
-    Ok(())
+    let operation = Operation::TrashAccount {
+        account_id,
+        deleted_at: Utc::now().to_rfc3339(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)
 }
 
 #[tauri::command]
@@ -466,47 +1777,82 @@ pub fn link_services_to_account(
     service_ids: Vec<String>,
     app_state: State<AppState>,
     session_state: State<SessionState>,
-) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
-
-    if let Some(account) = vault.accounts.iter_mut().find(|a| a.id == account_id) {
-        account.linked_services.extend(service_ids.into_iter());
+) -> Result<Account, CommandError> {
+    // Recorded as an update of the account, so it counts as a change to it.
+    let account = {
+        let vault_guard = app_state.0.lock().unwrap();
+        let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+        let existing = find_account(vault, &account_id)?;
+        let mut account = existing.clone();
+        account.linked_services.extend(service_ids);
         account.linked_services.sort();
         account.linked_services.dedup();
-    } else {
-        return Err(CommandError::AccountNotFound(account_id));
-    }
-
-    drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
+        account.timestamps = existing.timestamps.updated(&Utc::now().to_rfc3339());
+        account
+    };
 
-    Ok(())
+    let operation = Operation::UpdateAccount {
+        account: account.clone(),
+    };
+    apply_operation(&path, operation, &app_state, &session_state)?;
+    Ok(account)
 }
 
 #[tauri::command]
-pub fn change_master_password(
+pub async fn change_master_password(
+    old_password: String,
+    new_password: String,
+    cipher: Option<Cipher>,
+    keyfile_path: Option<String>,
+    app: AppHandle,
+) -> Result<(), CommandError> {
+    run_blocking(app, move |app| {
+        change_master_password_blocking(
+            app.state(),
+            old_password,
+            new_password,
+            cipher,
+            keyfile_path,
+            app.state(),
+            app.state(),
+        )
+    })
+    .await
+}
+
+fn change_master_password_blocking(
     path: State<StoragePath>,
     old_password: String,
     new_password: String,
+    cipher: Option<Cipher>,
+    keyfile_path: Option<String>,
     app_state: State<AppState>,
     session_state: State<SessionState>,
 ) -> Result<(), CommandError> {
     let vault_guard = app_state.0.lock().unwrap();
     let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
 
-    let mut password_guard = session_state.master_password.lock().unwrap();
-    let session_password = password_guard.as_ref().ok_or(CommandError::VaultLocked)?;
+    let mut keys_guard = session_state.vault_keys.lock().unwrap();
+    let mut keys = keys_guard.clone().ok_or(CommandError::VaultLocked)?;
 
-    if session_password.0 != old_password {
+    if !keys.verify_password(&MasterPassword(old_password))? {
         return Err(CommandError::InvalidOldPassword);
     }
-    
+
     let new_master_password = MasterPassword(new_password);
+    let mut encryption = keys.settings();
+    if let Some(cipher) = cipher {
+        encryption.cipher = cipher;
+    }
 
-    storage::save_vault(&path, &vault, &new_master_password)?;
+    // The new credentials are the new password plus `keyfile_path`, if any;
+    // omitting it removes the keyfile requirement. Only the data key is
+    // re-wrapped; the payload key stays the same.
+    let new_keyfile = read_optional_keyfile(keyfile_path)?;
+    keys.rewrap_password(&new_master_password, new_keyfile.as_ref(), &encryption)?;
+    save_vault_checked(&path, vault, &keys, &session_state)?;
 
-    *password_guard = Some(new_master_password);
+    *keys_guard = Some(keys);
 
     Ok(())
 }
@@ -549,10 +1895,11 @@ pub fn bulk_create_accounts(
     app_state: State<AppState>,
     session_state: State<SessionState>,
 ) -> Result<(), CommandError> {
-    let mut vault_guard = app_state.0.lock().unwrap();
-    let vault = vault_guard.as_mut().ok_or(CommandError::VaultLocked)?;
+    let vault_guard = app_state.0.lock().unwrap();
+    let vault = vault_guard.as_ref().ok_or(CommandError::VaultLocked)?;
 
     let mut created_accounts = Vec::new();
+    let now = Utc::now().to_rfc3339();
 
     // Create accounts
     for i in 0..request.account_config.count {
@@ -565,10 +1912,10 @@ pub fn bulk_create_accounts(
             notes: request.account_config.notes.clone(),
             tags: request.account_config.tags.clone(),
             linked_services: Vec::new(),
+            timestamps: Timestamps::new(&now),
         };
         
-        created_accounts.push(account.clone());
-        vault.accounts.push(account);
+        created_accounts.push(account);
     }
 
     // Link existing services if requested
@@ -594,10 +1941,8 @@ pub fn bulk_create_accounts(
                     s.label == service_name && s.service_type_id == service_config.service_type_id
                 ) {
                     // Link existing service to account
-                    if let Some(vault_account) = vault.accounts.iter_mut().find(|a| a.id == account.id) {
-                        if !vault_account.linked_services.contains(&existing_service.id) {
-                            vault_account.linked_services.push(existing_service.id.clone());
-                        }
+                    if !account.linked_services.contains(&existing_service.id) {
+                        account.linked_services.push(existing_service.id.clone());
                     }
                 }
             }
@@ -605,6 +1950,63 @@ pub fn bulk_create_accounts(
     }
 
     drop(vault_guard);
-    save_vault_with_session_password(&path, &app_state, &session_state)?;
-    Ok(())
+    // The accounts are recorded as one entry, with their links already in place.
+    let operation = Operation::AddAccounts {
+        accounts: created_accounts,
+    };
+    apply_operation(&path, operation, &app_state, &session_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BackupSettings, TrashedRecord};
+
+    fn trashed_account(id: &str, deleted_at: &str) -> TrashedItem {
+        TrashedItem {
+            record: TrashedRecord::Account {
+                account: Account {
+                    id: id.to_string(),
+                    label: id.to_string(),
+                    notes: String::new(),
+                    tags: Vec::new(),
+                    linked_services: Vec::new(),
+                    timestamps: Timestamps::default(),
+                },
+            },
+            deleted_at: deleted_at.to_string(),
+        }
+    }
+
+    #[test]
+    fn purges_trash_past_the_retention_period() {
+        let now = Utc::now();
+        let days_ago = |days: i64| (now - chrono::Duration::days(days)).to_rfc3339();
+        let mut vault = Vault {
+            version: migrations::CURRENT_VAULT_VERSION.to_string(),
+            service_types: Vec::new(),
+            services: Vec::new(),
+            accounts: Vec::new(),
+            settings: Settings {
+                auto_lock_minutes: 5,
+                backup: BackupSettings::default(),
+                trash_retention_days: 30,
+            },
+            trash: vec![
+                trashed_account("old", &days_ago(31)),
+                trashed_account("recent", &days_ago(29)),
+                trashed_account("unknown", "not a time"),
+            ],
+        };
+
+        let expired = expired_trash(&vault, now);
+        assert_eq!(expired.iter().map(|key| key.id.as_str()).collect::<Vec<_>>(), ["old"]);
+        Operation::PurgeTrash { items: expired }.apply(&mut vault).unwrap();
+        let kept: Vec<&str> = vault.trash.iter().map(TrashedItem::id).collect();
+        assert_eq!(kept, ["recent", "unknown"]);
+
+        // With no retention period, the trash is kept until it is emptied.
+        vault.settings.trash_retention_days = 0;
+        assert!(expired_trash(&vault, now + chrono::Duration::days(365)).is_empty());
+    }
 }
@@ -1,32 +1,73 @@
 //! `crypto.rs`: Handles all cryptographic operations for the application.
 
+use argon2::Argon2;
 use base64::{engine::general_purpose, Engine as _};
-use lazy_static::lazy_static;
+use blahaj::{Share, Sharks};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use log::warn;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use ring::aead::{
     self, BoundKey, Nonce as RingNonce, NonceSequence, OpeningKey, SealingKey, UnboundKey,
 };
+use ring::digest::{self, SHA256};
 use scrypt::{scrypt, Params};
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-// --- Constants & Static ---
+// --- Constants ---
 
+/// Version of the on-disk envelope format written by this build.
+pub const ENVELOPE_VERSION: u32 = 6;
+/// Envelopes written before the header was versioned carry no `version` field.
+const LEGACY_ENVELOPE_VERSION: u32 = 1;
+/// First envelope version whose header is authenticated as associated data.
+const AUTHENTICATED_HEADER_VERSION: u32 = 3;
+/// First envelope version that records its cipher.
+const CIPHER_HEADER_VERSION: u32 = 4;
+
+// Scrypt parameters used by every vault written before the KDF was configurable.
 const SCRYPT_LOG_N: u8 = 14;
 const SCRYPT_R: u32 = 8;
 const SCRYPT_P: u32 = 1;
+
+// Argon2id defaults for newly created vaults.
+const ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+
+// Bounds for calibrated parameters, so a very fast or very slow benchmark
+// never produces unusable settings. Both maximums need 1 GiB of memory.
+const CALIBRATION_SCRYPT_LOG_N_RANGE: (u8, u8) = (SCRYPT_LOG_N, 20);
+const CALIBRATION_ARGON2_MEMORY_KIB_RANGE: (u32, u32) = (19 * 1024, 1024 * 1024);
+
+// Upper bounds for KDF settings read from a file, so a crafted header can't
+// make unlocking allocate or compute without limit. Everything calibration
+// suggests stays within them.
+const MAX_KDF_MEMORY_BYTES: u64 = 1024 * 1024 * 1024;
+const MAX_SCRYPT_R: u32 = 16;
+const MAX_KDF_PARALLELISM: u32 = 16;
+const MAX_ARGON2_ITERATIONS: u32 = 16;
+
 const KEY_LEN: usize = 32;
 const NONCE_LEN: usize = 12;
+const XNONCE_LEN: usize = 24;
 const SALT_LEN: usize = 16;
-
-lazy_static! {
-    static ref SCRYPT_PARAMS: Params =
-        Params::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, KEY_LEN).unwrap();
-}
+const RECOVERY_KEY_LEN: usize = 24;
+/// Hex digits per dash-separated group in a formatted recovery key.
+const RECOVERY_KEY_GROUP_LEN: usize = 6;
+/// Version of the recovery share format written by this build.
+const RECOVERY_SHARE_VERSION: u8 = 2;
+/// A recovery share is the format version, the threshold, the share's x
+/// coordinate and one byte per byte of the recovery key.
+const RECOVERY_SHARE_LEN: usize = RECOVERY_KEY_LEN + 3;
 
 // --- Structs ---
 
-#[derive(Zeroize, ZeroizeOnDrop)]
+#[derive(Zeroize, ZeroizeOnDrop, Clone)]
 pub struct DerivedKey {
     pub key: [u8; KEY_LEN],
 }
@@ -34,12 +75,595 @@ pub struct DerivedKey {
 #[derive(Zeroize, ZeroizeOnDrop)]
 pub struct MasterPassword(pub String);
 
+/// SHA-256 of a keyfile's contents. Only the hash is kept, never the file itself.
+#[derive(Zeroize, ZeroizeOnDrop, Clone)]
+pub struct KeyfileHash([u8; KEY_LEN]);
+
+impl KeyfileHash {
+    pub fn from_contents(contents: &[u8]) -> Self {
+        let mut hash = [0u8; KEY_LEN];
+        hash.copy_from_slice(digest::digest(&SHA256, contents).as_ref());
+        KeyfileHash(hash)
+    }
+}
+
+/// Random key that encrypts the vault payload. It is stored only in wrapped
+/// form, once per way of unlocking the vault.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct DataKey([u8; KEY_LEN]);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        DataKey(key)
+    }
+}
+
+/// Key derivation function and its cost parameters, stored in the vault header.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "algorithm", rename_all = "camelCase")]
+pub enum KdfParams {
+    #[serde(rename_all = "camelCase")]
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    #[serde(rename_all = "camelCase")]
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl KdfParams {
+    /// The fixed scrypt settings used by vaults that predate the versioned header.
+    pub fn legacy_scrypt() -> Self {
+        KdfParams::Scrypt {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Approximate memory needed by one derivation, in bytes. Used to compare
+    /// settings across different algorithms. Saturates for settings read from
+    /// a file that no machine could run.
+    fn memory_cost(&self) -> u64 {
+        match *self {
+            KdfParams::Scrypt { log_n, r, .. } => 1u64
+                .checked_shl(u32::from(log_n))
+                .unwrap_or(u64::MAX)
+                .saturating_mul(128 * u64::from(r)),
+            KdfParams::Argon2id { memory_kib, .. } => u64::from(memory_kib) * 1024,
+        }
+    }
+
+    /// Returns `true` if these settings are cheaper to brute-force than `baseline`.
+    pub fn is_weaker_than(&self, baseline: &KdfParams) -> bool {
+        match (self, baseline) {
+            (
+                KdfParams::Scrypt { log_n, r, p },
+                KdfParams::Scrypt {
+                    log_n: base_log_n,
+                    r: base_r,
+                    p: base_p,
+                },
+            ) => log_n < base_log_n || r < base_r || p < base_p,
+            (
+                KdfParams::Argon2id {
+                    memory_kib,
+                    iterations,
+                    ..
+                },
+                KdfParams::Argon2id {
+                    memory_kib: base_memory_kib,
+                    iterations: base_iterations,
+                    ..
+                },
+            ) => memory_kib < base_memory_kib || iterations < base_iterations,
+            _ => self.memory_cost() < baseline.memory_cost(),
+        }
+    }
+
+    /// Fails for settings beyond the limits above, before any memory is
+    /// allocated for them.
+    fn check_limits(&self) -> Result<(), CryptoError> {
+        let within_limits = match *self {
+            KdfParams::Scrypt { log_n, r, p } => {
+                log_n <= CALIBRATION_SCRYPT_LOG_N_RANGE.1
+                    && r <= MAX_SCRYPT_R
+                    && p <= MAX_KDF_PARALLELISM
+                    && self.memory_cost() <= MAX_KDF_MEMORY_BYTES
+            }
+            KdfParams::Argon2id {
+                iterations,
+                parallelism,
+                ..
+            } => {
+                iterations <= MAX_ARGON2_ITERATIONS
+                    && parallelism <= MAX_KDF_PARALLELISM
+                    && self.memory_cost() <= MAX_KDF_MEMORY_BYTES
+            }
+        };
+        if !within_limits {
+            return Err(CryptoError::InvalidHeader(format!(
+                "KDF settings {:?} exceed the supported maximum",
+                self
+            )));
+        }
+        Ok(())
+    }
+
+    /// The weakest settings of the same algorithm that calibration can
+    /// suggest. Anything at or above it was a deliberate choice and is kept.
+    pub fn minimum(&self) -> KdfParams {
+        match self {
+            KdfParams::Scrypt { .. } => KdfParams::Scrypt {
+                log_n: CALIBRATION_SCRYPT_LOG_N_RANGE.0,
+                r: SCRYPT_R,
+                p: SCRYPT_P,
+            },
+            KdfParams::Argon2id { .. } => KdfParams::Argon2id {
+                memory_kib: CALIBRATION_ARGON2_MEMORY_KIB_RANGE.0,
+                iterations: ARGON2_ITERATIONS,
+                parallelism: ARGON2_PARALLELISM,
+            },
+        }
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams::Argon2id {
+            memory_kib: ARGON2_MEMORY_KIB,
+            iterations: ARGON2_ITERATIONS,
+            parallelism: ARGON2_PARALLELISM,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum KdfAlgorithm {
+    Scrypt,
+    Argon2id,
+}
+
+/// Suggested KDF settings for this machine and how long they took to run.
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KdfCalibration {
+    pub kdf: KdfParams,
+    pub measured_ms: u64,
+}
+
+/// AEAD cipher used for the vault payload. Envelopes that don't name one
+/// were written with AES-256-GCM.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    /// Uses a 24-byte nonce, so random nonces are safe for any number of saves.
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn nonce_len(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm | Cipher::ChaCha20Poly1305 => NONCE_LEN,
+            Cipher::XChaCha20Poly1305 => XNONCE_LEN,
+        }
+    }
+
+    /// Encrypts `data` under `key` with a fresh random nonce, returning the
+    /// nonce followed by the ciphertext and tag.
+    fn seal(self, key: &[u8; KEY_LEN], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut nonce_bytes = vec![0u8; self.nonce_len()];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let sealed = match self {
+            Cipher::Aes256Gcm | Cipher::ChaCha20Poly1305 => {
+                let nonce = RingNonce::try_assume_unique_for_key(&nonce_bytes)
+                    .map_err(|_| CryptoError::Ring)?;
+                let unbound_key =
+                    UnboundKey::new(self.ring_algorithm(), key).map_err(|_| CryptoError::Ring)?;
+                let mut sealing_key = SealingKey::new(unbound_key, OneNonceSequence(Some(nonce)));
+
+                let mut in_out = data.to_vec();
+                sealing_key
+                    .seal_in_place_append_tag(aead::Aad::from(aad), &mut in_out)
+                    .map_err(|_| CryptoError::Ring)?;
+                in_out
+            }
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(key.into())
+                .encrypt(XNonce::from_slice(&nonce_bytes), Payload { msg: data, aad })
+                .map_err(|_| CryptoError::Ring)?,
+        };
+
+        // We need to prepend the nonce to the ciphertext to use it for decryption
+        nonce_bytes.extend_from_slice(&sealed);
+        Ok(nonce_bytes)
+    }
+
+    /// Reverses `seal`. A failed tag check means the key or associated data is wrong.
+    fn open(self, key: &[u8; KEY_LEN], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < self.nonce_len() {
+            return Err(CryptoError::InvalidHeader("ciphertext is truncated".to_string()));
+        }
+        let (nonce_bytes, ciphertext) = sealed.split_at(self.nonce_len());
+
+        match self {
+            Cipher::Aes256Gcm | Cipher::ChaCha20Poly1305 => {
+                let nonce = RingNonce::try_assume_unique_for_key(nonce_bytes)
+                    .map_err(|_| CryptoError::Slice)?;
+                let unbound_key =
+                    UnboundKey::new(self.ring_algorithm(), key).map_err(|_| CryptoError::Ring)?;
+                let mut opening_key = OpeningKey::new(unbound_key, OneNonceSequence(Some(nonce)));
+
+                let mut in_out = ciphertext.to_vec();
+                let decrypted_data = opening_key
+                    .open_in_place(aead::Aad::from(aad), &mut in_out)
+                    .map_err(|_| CryptoError::AuthenticationFailed)?;
+                Ok(decrypted_data.to_vec())
+            }
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(key.into())
+                .decrypt(XNonce::from_slice(nonce_bytes), Payload { msg: ciphertext, aad })
+                .map_err(|_| CryptoError::AuthenticationFailed),
+        }
+    }
+
+    fn ring_algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            _ => &aead::AES_256_GCM,
+        }
+    }
+}
+
+/// The KDF and cipher a vault is encrypted with. Kept for the session so
+/// every save reuses the settings the vault was opened with.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct EncryptionSettings {
+    pub kdf: KdfParams,
+    pub cipher: Cipher,
+}
+
+/// The data key wrapped under a key derived from some secret other than the
+/// master password, such as a recovery key.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct KeySlot {
+    pub kdf: KdfParams,
+    #[serde(default)]
+    pub cipher: Cipher,
+    pub salt: String,
+    pub wrapped_key: Vec<u8>,
+}
+
+impl KeySlot {
+    fn wrap(
+        data_key: &DataKey,
+        secret: &MasterPassword,
+        kdf: &KdfParams,
+        cipher: Cipher,
+    ) -> Result<Self, CryptoError> {
+        let salt = random_salt();
+        let kek = derive_key(secret, None, &salt, kdf)?;
+        Ok(KeySlot {
+            kdf: kdf.clone(),
+            cipher,
+            salt: general_purpose::STANDARD.encode(salt),
+            wrapped_key: cipher.seal(&kek.key, &[], &data_key.0)?,
+        })
+    }
+
+    fn unwrap(&self, secret: &MasterPassword) -> Result<DataKey, CryptoError> {
+        let kek = derive_key(secret, None, &decode_salt(&self.salt)?, &self.kdf)?;
+        open_data_key(self.cipher, &kek, &self.wrapped_key)
+    }
+}
+
+/// The password's copy of the data key. It is stored flattened in the
+/// envelope header (`kdf`, `keyfile`, `salt`, `wrapped_key`) and wrapped with
+/// the envelope's cipher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordSlot {
+    pub kdf: KdfParams,
+    pub keyfile: bool,
+    pub salt: String,
+    pub wrapped_key: Vec<u8>,
+}
+
+impl PasswordSlot {
+    /// Wraps `data_key` under a key derived with a fresh salt. The derived key
+    /// is returned as well, for verifying the password later.
+    fn wrap(
+        data_key: &DataKey,
+        password: &MasterPassword,
+        keyfile: Option<&KeyfileHash>,
+        settings: &EncryptionSettings,
+    ) -> Result<(Self, DerivedKey), CryptoError> {
+        let salt = random_salt();
+        let kek = derive_key(password, keyfile, &salt, &settings.kdf)?;
+        let slot = PasswordSlot {
+            kdf: settings.kdf.clone(),
+            keyfile: keyfile.is_some(),
+            salt: general_purpose::STANDARD.encode(salt),
+            wrapped_key: settings.cipher.seal(&kek.key, &[], &data_key.0)?,
+        };
+        Ok((slot, kek))
+    }
+}
+
+/// Everything needed to write the vault without the master password: the
+/// random data key that encrypts the payload and its wrapped copies. Held for
+/// the session after unlock, so saves don't have to run the KDF. The master
+/// password itself is never kept, only the key derived from it.
+#[derive(Clone)]
+pub struct VaultKeys {
+    data_key: Locked<DataKey>,
+    /// The key derived from the master password (and keyfile) that wraps the data key.
+    password_key: Locked<DerivedKey>,
+    keyfile: Option<Locked<KeyfileHash>>,
+    cipher: Cipher,
+    password: PasswordSlot,
+    recovery: Option<KeySlot>,
+}
+
+impl VaultKeys {
+    /// Creates a fresh data key wrapped under the password (and keyfile).
+    pub fn new(
+        password: &MasterPassword,
+        keyfile: Option<&KeyfileHash>,
+        settings: &EncryptionSettings,
+    ) -> Result<Self, CryptoError> {
+        let data_key = Locked::new(DataKey::generate());
+        let (slot, password_key) = PasswordSlot::wrap(&data_key, password, keyfile, settings)?;
+        Ok(VaultKeys {
+            data_key,
+            password_key: Locked::new(password_key),
+            keyfile: keyfile.cloned().map(Locked::new),
+            cipher: settings.cipher,
+            password: slot,
+            recovery: None,
+        })
+    }
+
+    pub fn settings(&self) -> EncryptionSettings {
+        EncryptionSettings {
+            kdf: self.password.kdf.clone(),
+            cipher: self.cipher,
+        }
+    }
+
+    pub fn uses_keyfile(&self) -> bool {
+        self.password.keyfile
+    }
+
+    /// Wraps the existing data key under new credentials or settings. The
+    /// payload key doesn't change, so a recovery key stays valid.
+    pub fn rewrap_password(
+        &mut self,
+        password: &MasterPassword,
+        keyfile: Option<&KeyfileHash>,
+        settings: &EncryptionSettings,
+    ) -> Result<(), CryptoError> {
+        let (slot, password_key) = PasswordSlot::wrap(&self.data_key, password, keyfile, settings)?;
+        self.password = slot;
+        self.password_key = Locked::new(password_key);
+        self.keyfile = keyfile.cloned().map(Locked::new);
+        self.cipher = settings.cipher;
+        Ok(())
+    }
+
+    /// Replaces the data key with a fresh one, wrapped under the same password
+    /// key. Journal entries and earlier copies of the vault, such as backups,
+    /// stay encrypted under the old key, so the vault has to be saved in full.
+    fn rotate_data_key(&mut self) -> Result<(), CryptoError> {
+        let data_key = Locked::new(DataKey::generate());
+        self.password.wrapped_key = self.cipher.seal(&self.password_key.key, &[], &data_key.0)?;
+        self.data_key = data_key;
+        Ok(())
+    }
+
+    /// Checks `password` against the current master password by deriving its
+    /// key again and comparing the keys in constant time.
+    pub fn verify_password(&self, password: &MasterPassword) -> Result<bool, CryptoError> {
+        let salt = decode_salt(&self.password.salt)?;
+        let derived_key = derive_key(password, self.keyfile.as_deref(), &salt, &self.password.kdf)?;
+        Ok(derived_key.key.ct_eq(&self.password_key.key).into())
+    }
+
+    /// Replaces the data key and wraps it under a newly generated recovery key,
+    /// so earlier recovery keys no longer open the vault once it is saved.
+    /// The formatted key is returned so it can be shown once.
+    pub fn set_recovery_key(&mut self) -> Result<MasterPassword, CryptoError> {
+        let recovery_bytes = self.replace_recovery_secret()?;
+        Ok(format_recovery_key(recovery_bytes.as_ref()))
+    }
+
+    /// Like `set_recovery_key`, but the recovery key is split into `count`
+    /// shares, any `threshold` of which rebuild it. Fewer shares reveal
+    /// nothing about the key.
+    pub fn set_recovery_shares(
+        &mut self,
+        threshold: u8,
+        count: u8,
+    ) -> Result<Vec<MasterPassword>, CryptoError> {
+        if threshold == 0 || threshold > count {
+            return Err(CryptoError::InvalidShares(format!(
+                "a threshold of {} out of {} shares is not possible",
+                threshold, count
+            )));
+        }
+
+        let recovery_bytes = self.replace_recovery_secret()?;
+        let shares = Sharks(threshold)
+            .dealer(recovery_bytes.as_ref())
+            .take(count as usize)
+            .map(|share| {
+                let mut bytes = Zeroizing::new(Vec::with_capacity(RECOVERY_SHARE_LEN));
+                bytes.push(RECOVERY_SHARE_VERSION);
+                bytes.push(threshold);
+                bytes.extend_from_slice(&Vec::from(&share));
+                format_recovery_key(&bytes)
+            })
+            .collect();
+        Ok(shares)
+    }
+
+    /// Wraps a fresh data key under a new random recovery secret and returns
+    /// the secret. A leaked recovery key could otherwise still unwrap the
+    /// data key from any copy of the vault saved later.
+    fn replace_recovery_secret(&mut self) -> Result<Zeroizing<[u8; RECOVERY_KEY_LEN]>, CryptoError> {
+        self.rotate_data_key()?;
+
+        let mut recovery_bytes = Zeroizing::new([0u8; RECOVERY_KEY_LEN]);
+        OsRng.fill_bytes(recovery_bytes.as_mut());
+        let recovery_key = format_recovery_key(recovery_bytes.as_ref());
+
+        let settings = self.settings();
+        self.recovery = Some(KeySlot::wrap(
+            &self.data_key,
+            &normalize_recovery_key(&recovery_key.0)?,
+            &settings.kdf,
+            settings.cipher,
+        )?);
+        Ok(recovery_bytes)
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct EncryptedVault {
+    #[serde(default = "legacy_envelope_version")]
+    pub version: u32,
+    #[serde(default = "KdfParams::legacy_scrypt")]
+    pub kdf: KdfParams,
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// The key was derived from the password combined with a keyfile.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub keyfile: bool,
     pub salt: String,
+    /// The data key wrapped under the password-derived key. Envelopes written
+    /// before version 5 (and version 5 ones without a recovery key) have none;
+    /// their password-derived key encrypts the payload directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery: Option<KeySlot>,
     pub ciphertext: Vec<u8>,
 }
 
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn legacy_envelope_version() -> u32 {
+    LEGACY_ENVELOPE_VERSION
+}
+
+/// Envelope metadata bound to the ciphertext as AEAD associated data.
+/// Every header field of `EncryptedVault` must be listed here.
+#[derive(serde::Serialize)]
+struct EnvelopeHeader<'a> {
+    version: u32,
+    kdf: &'a KdfParams,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cipher: Option<Cipher>,
+    #[serde(skip_serializing_if = "is_false")]
+    keyfile: bool,
+    salt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wrapped_key: Option<&'a [u8]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery: Option<&'a KeySlot>,
+}
+
+impl EncryptedVault {
+    /// Whether this envelope should be rewritten with the current format.
+    pub fn needs_upgrade(&self) -> bool {
+        self.version < ENVELOPE_VERSION || self.kdf_needs_upgrade()
+    }
+
+    /// Whether the password slot should be re-wrapped with the default KDF.
+    /// Legacy envelopes got fixed scrypt settings nobody chose; newer ones
+    /// keep their settings unless they fall below the calibration minimum.
+    pub fn kdf_needs_upgrade(&self) -> bool {
+        self.version == LEGACY_ENVELOPE_VERSION || self.kdf.is_weaker_than(&self.kdf.minimum())
+    }
+
+    /// The associated data for this envelope. Headers written before version 3
+    /// were not authenticated and use empty associated data.
+    fn header_aad(&self) -> Result<Vec<u8>, CryptoError> {
+        if self.version < AUTHENTICATED_HEADER_VERSION {
+            return Ok(Vec::new());
+        }
+        let header = EnvelopeHeader {
+            version: self.version,
+            kdf: &self.kdf,
+            cipher: (self.version >= CIPHER_HEADER_VERSION).then_some(self.cipher),
+            keyfile: self.keyfile,
+            salt: &self.salt,
+            wrapped_key: self.wrapped_key.as_deref(),
+            recovery: self.recovery.as_ref(),
+        };
+        serde_json::to_vec(&header).map_err(|e| CryptoError::InvalidHeader(e.to_string()))
+    }
+}
+
+// --- Locked Memory ---
+
+/// A secret kept on the heap and locked into RAM where the OS allows it, so it
+/// is never written to swap. It is zeroized before its memory is released.
+pub struct Locked<T: Zeroize> {
+    // Dropped before `value`, which `Drop` has already zeroized by then.
+    _guard: Option<region::LockGuard>,
+    value: Box<T>,
+}
+
+impl<T: Zeroize> Locked<T> {
+    pub fn new(value: T) -> Self {
+        let value = Box::new(value);
+        // Locking fails when the memlock limit is reached; the secret is then
+        // only zeroized, which is still better than refusing to unlock.
+        let guard = match region::lock(&*value as *const T, std::mem::size_of::<T>()) {
+            Ok(guard) => Some(guard),
+            Err(e) => {
+                warn!("Failed to lock key material in memory: {}", e);
+                None
+            }
+        };
+        Locked { _guard: guard, value }
+    }
+}
+
+impl<T: Zeroize> Deref for Locked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Locked<T> {
+    fn clone(&self) -> Self {
+        Locked::new(T::clone(&self.value))
+    }
+}
+
+impl<T: Zeroize> Drop for Locked<T> {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
 // --- Nonce Sequence ---
 
 struct OneNonceSequence(Option<RingNonce>);
@@ -58,8 +682,30 @@ pub enum CryptoError {
     Scrypt(#[from] scrypt::errors::InvalidParams),
     #[error("Scrypt failed during key derivation")]
     ScryptDerivation,
+    #[error("Argon2 failed: {0}")]
+    Argon2(argon2::Error),
+    #[error("Unsupported vault format version: {0}")]
+    UnsupportedVersion(u32),
     #[error("Encryption/Decryption failed: Ring error")]
     Ring,
+    #[error("Decryption failed: the password is wrong or the vault has been tampered with")]
+    AuthenticationFailed,
+    #[error("The vault header has been tampered with")]
+    HeaderTampered,
+    #[error("Invalid vault header: {0}")]
+    InvalidHeader(String),
+    #[error("This vault requires a keyfile to unlock")]
+    KeyfileRequired,
+    #[error("This vault has no recovery key")]
+    NoRecoveryKey,
+    #[error("The recovery key is not in the expected format")]
+    InvalidRecoveryKey,
+    #[error("The recovery shares are invalid: {0}")]
+    InvalidShares(String),
+    #[error("The vault was re-encrypted with different credentials; unlock it again")]
+    CredentialsChanged,
+    #[error("This copy of the vault has no data key; it opens only with the password it was saved with")]
+    NoDataKey,
     #[error("Base64 decoding failed: {0}")]
     Base64(#[from] base64::DecodeError),
     #[error("Slice conversion failed")]
@@ -68,74 +714,636 @@ pub enum CryptoError {
 
 // --- Public Functions ---
 
+/// Builds the KDF input. Without a keyfile this is the password itself, so
+/// existing vaults keep working; with one it is `SHA-256(password) || keyfile hash`.
+fn composite_secret(password: &MasterPassword, keyfile: Option<&KeyfileHash>) -> Zeroizing<Vec<u8>> {
+    match keyfile {
+        None => Zeroizing::new(password.0.as_bytes().to_vec()),
+        Some(keyfile) => {
+            let mut secret = Zeroizing::new(Vec::with_capacity(2 * KEY_LEN));
+            secret.extend_from_slice(digest::digest(&SHA256, password.0.as_bytes()).as_ref());
+            secret.extend_from_slice(&keyfile.0);
+            secret
+        }
+    }
+}
+
 pub fn derive_key(
     password: &MasterPassword,
+    keyfile: Option<&KeyfileHash>,
     salt: &[u8; SALT_LEN],
+    kdf: &KdfParams,
 ) -> Result<DerivedKey, CryptoError> {
+    kdf.check_limits()?;
+    let secret = composite_secret(password, keyfile);
     let mut key = [0u8; KEY_LEN];
-    scrypt(password.0.as_bytes(), salt, &SCRYPT_PARAMS, &mut key)
-        .map_err(|_| CryptoError::ScryptDerivation)?;
+    match *kdf {
+        KdfParams::Scrypt { log_n, r, p } => {
+            let params = Params::new(log_n, r, p, KEY_LEN)?;
+            scrypt(&secret, salt, &params, &mut key)
+                .map_err(|_| CryptoError::ScryptDerivation)?;
+        }
+        KdfParams::Argon2id {
+            memory_kib,
+            iterations,
+            parallelism,
+        } => {
+            let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(KEY_LEN))
+                .map_err(CryptoError::Argon2)?;
+            Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password_into(&secret, salt, &mut key)
+                .map_err(CryptoError::Argon2)?;
+        }
+    }
     Ok(DerivedKey { key })
 }
 
-pub fn encrypt_vault(
-    data: &[u8],
-    password: &MasterPassword,
-) -> Result<EncryptedVault, CryptoError> {
+fn time_derivation(kdf: &KdfParams) -> Result<Duration, CryptoError> {
+    let password = MasterPassword("accman-kdf-calibration".to_string());
+    let salt = random_salt();
+
+    let start = Instant::now();
+    derive_key(&password, None, &salt, kdf)?;
+    Ok(start.elapsed())
+}
+
+/// Benchmarks `derive_key` on this machine and suggests parameters for the
+/// given algorithm that take roughly `target` to derive a key.
+///
+/// Derivation time scales linearly with scrypt's `N` and with Argon2's memory
+/// cost, so a single cheap run is extrapolated and the result is timed once more.
+pub fn calibrate_kdf(
+    algorithm: KdfAlgorithm,
+    target: Duration,
+) -> Result<KdfCalibration, CryptoError> {
+    let kdf = match algorithm {
+        KdfAlgorithm::Scrypt => {
+            let (min_log_n, max_log_n) = CALIBRATION_SCRYPT_LOG_N_RANGE;
+            let base = KdfParams::Scrypt {
+                log_n: min_log_n,
+                r: SCRYPT_R,
+                p: SCRYPT_P,
+            };
+            let elapsed = time_derivation(&base)?.max(Duration::from_millis(1));
+            let ratio = target.as_secs_f64() / elapsed.as_secs_f64();
+            let extra = if ratio > 1.0 { ratio.log2().floor() as u8 } else { 0 };
+            KdfParams::Scrypt {
+                log_n: min_log_n.saturating_add(extra).min(max_log_n),
+                r: SCRYPT_R,
+                p: SCRYPT_P,
+            }
+        }
+        KdfAlgorithm::Argon2id => {
+            let (min_memory_kib, max_memory_kib) = CALIBRATION_ARGON2_MEMORY_KIB_RANGE;
+            let base = KdfParams::Argon2id {
+                memory_kib: min_memory_kib,
+                iterations: ARGON2_ITERATIONS,
+                parallelism: ARGON2_PARALLELISM,
+            };
+            let elapsed = time_derivation(&base)?.max(Duration::from_millis(1));
+            let ratio = target.as_secs_f64() / elapsed.as_secs_f64();
+            // Round down to whole MiB to keep the stored parameters readable.
+            let memory_kib = ((f64::from(min_memory_kib) * ratio) as u32 / 1024) * 1024;
+            KdfParams::Argon2id {
+                memory_kib: memory_kib.clamp(min_memory_kib, max_memory_kib),
+                iterations: ARGON2_ITERATIONS,
+                parallelism: ARGON2_PARALLELISM,
+            }
+        }
+    };
+
+    let measured = time_derivation(&kdf)?;
+    Ok(KdfCalibration {
+        kdf,
+        measured_ms: measured.as_millis() as u64,
+    })
+}
+
+fn random_salt() -> [u8; SALT_LEN] {
     let mut salt = [0u8; SALT_LEN];
     OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+fn decode_salt(salt: &str) -> Result<[u8; SALT_LEN], CryptoError> {
+    general_purpose::STANDARD
+        .decode(salt)?
+        .try_into()
+        .map_err(|_| CryptoError::InvalidHeader("salt has the wrong length".to_string()))
+}
+
+fn open_data_key(cipher: Cipher, kek: &DerivedKey, wrapped_key: &[u8]) -> Result<DataKey, CryptoError> {
+    let key = Zeroizing::new(cipher.open(&kek.key, &[], wrapped_key)?);
+    let key: [u8; KEY_LEN] = key
+        .as_slice()
+        .try_into()
+        .map_err(|_| CryptoError::InvalidHeader("wrapped key has the wrong length".to_string()))?;
+    Ok(DataKey(key))
+}
+
+/// Decrypts the payload with a data key that was unwrapped, so the
+/// credentials were right: if the payload still fails to authenticate, the
+/// header it is bound to was changed.
+fn open_payload(cipher: Cipher, data_key: &DataKey, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    cipher.open(&data_key.0, aad, ciphertext).map_err(|e| match e {
+            CryptoError::AuthenticationFailed => CryptoError::HeaderTampered,
+            e => e,
+        })
+}
 
-    let derived_key = derive_key(password, &salt)?;
+/// Formats recovery key bytes as dash-separated groups of upper-case hex.
+fn format_recovery_key(bytes: &[u8]) -> MasterPassword {
+    let hex = Zeroizing::new(bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>());
+    let groups: Vec<&str> = hex
+        .as_bytes()
+        .chunks(RECOVERY_KEY_GROUP_LEN)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect();
+    MasterPassword(groups.join("-"))
+}
+
+/// Strips separators and whitespace from a typed recovery key, so it is
+/// accepted however the user copied it.
+fn normalize_recovery_key(input: &str) -> Result<MasterPassword, CryptoError> {
+    let normalized: String = input
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if normalized.len() != RECOVERY_KEY_LEN * 2 {
+        return Err(CryptoError::InvalidRecoveryKey);
+    }
+    Ok(MasterPassword(normalized))
+}
 
-    let mut nonce_bytes = [0u8; NONCE_LEN];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = RingNonce::assume_unique_for_key(nonce_bytes);
+/// Rebuilds a recovery key from shares made by `VaultKeys::set_recovery_shares`.
+/// A share entered twice is only counted once.
+pub fn combine_recovery_shares(shares: &[String]) -> Result<MasterPassword, CryptoError> {
+    let mut threshold = None;
+    let mut parsed: Vec<Share> = Vec::with_capacity(shares.len());
+    let mut seen = Vec::with_capacity(shares.len());
 
-    let unbound_key =
-        UnboundKey::new(&aead::AES_256_GCM, &derived_key.key).map_err(|_| CryptoError::Ring)?;
+    for share in shares {
+        let bytes = decode_recovery_share(share)?;
+        if *threshold.get_or_insert(bytes[0]) != bytes[0] {
+            return Err(CryptoError::InvalidShares(
+                "the shares come from different splits".to_string(),
+            ));
+        }
+        if seen.contains(&bytes[1]) {
+            continue;
+        }
+        seen.push(bytes[1]);
+        let share = Share::try_from(&bytes[1..])
+            .map_err(|e| CryptoError::InvalidShares(e.to_string()))?;
+        parsed.push(share);
+    }
 
-    let mut sealing_key = SealingKey::new(unbound_key, OneNonceSequence(Some(nonce)));
+    let threshold = threshold
+        .ok_or_else(|| CryptoError::InvalidShares("no shares were given".to_string()))?;
+    let secret = Zeroizing::new(
+        Sharks(threshold)
+            .recover(&parsed)
+            .map_err(|e| CryptoError::InvalidShares(e.to_string()))?,
+    );
+    Ok(format_recovery_key(&secret))
+}
 
-    let mut in_out = data.to_vec();
-    sealing_key
-        .seal_in_place_append_tag(aead::Aad::empty(), &mut in_out)
-        .map_err(|_| CryptoError::Ring)?;
+/// Parses a formatted recovery share, accepting the same separators as a
+/// recovery key. The version byte is checked and dropped, so the result
+/// starts with the threshold; unversioned shares are taken as they are.
+fn decode_recovery_share(input: &str) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let digits = Zeroizing::new(
+        input
+            .chars()
+            .filter(|c| c.is_ascii_hexdigit())
+            .collect::<String>(),
+    );
+    if digits.len() != RECOVERY_SHARE_LEN * 2 {
+        return Err(CryptoError::InvalidShares(
+            "a share has the wrong length".to_string(),
+        ));
+    }
 
-    // We need to prepend the nonce to the ciphertext to use it for decryption
-    let mut ciphertext_with_nonce = nonce_bytes.to_vec();
-    ciphertext_with_nonce.extend_from_slice(&in_out);
+    let mut bytes = Zeroizing::new(Vec::with_capacity(RECOVERY_SHARE_LEN));
+    for pair in digits.as_bytes().chunks(2) {
+        let pair = std::str::from_utf8(pair).unwrap_or_default();
+        // Only ASCII hex digits are left, so every pair parses.
+        bytes.push(u8::from_str_radix(pair, 16).unwrap_or_default());
+    }
 
-    Ok(EncryptedVault {
-        salt: general_purpose::STANDARD.encode(salt),
-        ciphertext: ciphertext_with_nonce,
-    })
+    if bytes[0] != RECOVERY_SHARE_VERSION {
+        return Err(CryptoError::InvalidShares(format!(
+            "a share uses format version {}, but this build only reads {}",
+            bytes[0], RECOVERY_SHARE_VERSION
+        )));
+    }
+    bytes.remove(0);
+    Ok(bytes)
+}
+
+/// Encrypts the vault payload under the data key. No key derivation happens
+/// here; the header carries the already wrapped copies from `keys`.
+pub fn encrypt_vault(data: &[u8], keys: &VaultKeys) -> Result<EncryptedVault, CryptoError> {
+    let mut encrypted_vault = EncryptedVault {
+        version: ENVELOPE_VERSION,
+        kdf: keys.password.kdf.clone(),
+        cipher: keys.cipher,
+        keyfile: keys.password.keyfile,
+        salt: keys.password.salt.clone(),
+        wrapped_key: Some(keys.password.wrapped_key.clone()),
+        recovery: keys.recovery.clone(),
+        ciphertext: Vec::new(),
+    };
+
+    let aad = encrypted_vault.header_aad()?;
+    encrypted_vault.ciphertext = keys.cipher.seal(&keys.data_key.0, &aad, data)?;
+
+    Ok(encrypted_vault)
+}
+
+fn check_envelope(encrypted_vault: &EncryptedVault) -> Result<(), CryptoError> {
+    if encrypted_vault.version > ENVELOPE_VERSION {
+        return Err(CryptoError::UnsupportedVersion(encrypted_vault.version));
+    }
+    if encrypted_vault.ciphertext.len() < encrypted_vault.cipher.nonce_len() {
+        return Err(CryptoError::InvalidHeader("ciphertext is truncated".to_string()));
+    }
+    if encrypted_vault.recovery.is_some() && encrypted_vault.wrapped_key.is_none() {
+        return Err(CryptoError::InvalidHeader("incomplete key slots".to_string()));
+    }
+    Ok(())
 }
 
+/// Decrypts the vault payload with the master password and returns the keys
+/// for later saves. Envelopes without a data key get a fresh one, wrapped
+/// under the same password-derived key, so they are converted on next save.
 pub fn decrypt_vault(
     encrypted_vault: EncryptedVault,
     password: &MasterPassword,
+    keyfile: Option<&KeyfileHash>,
+) -> Result<(Vec<u8>, VaultKeys), CryptoError> {
+    check_envelope(&encrypted_vault)?;
+    if encrypted_vault.keyfile && keyfile.is_none() {
+        return Err(CryptoError::KeyfileRequired);
+    }
+
+    let salt = decode_salt(&encrypted_vault.salt)?;
+    let aad = encrypted_vault.header_aad()?;
+
+    // A keyfile supplied for a vault that doesn't use one is ignored.
+    let keyfile = keyfile.filter(|_| encrypted_vault.keyfile);
+    let derived_key = derive_key(password, keyfile, &salt, &encrypted_vault.kdf)?;
+    let cipher = encrypted_vault.cipher;
+
+    let (data, data_key, wrapped_key) = match encrypted_vault.wrapped_key {
+        Some(wrapped_key) => {
+            let data_key = Locked::new(open_data_key(cipher, &derived_key, &wrapped_key)?);
+            let data = open_payload(cipher, &data_key, &aad, &encrypted_vault.ciphertext)?;
+            (data, data_key, wrapped_key)
+        }
+        None => {
+            let data = cipher.open(&derived_key.key, &aad, &encrypted_vault.ciphertext)?;
+            let data_key = Locked::new(DataKey::generate());
+            let wrapped_key = cipher.seal(&derived_key.key, &[], &data_key.0)?;
+            (data, data_key, wrapped_key)
+        }
+    };
+
+    let keys = VaultKeys {
+        data_key,
+        password_key: Locked::new(derived_key),
+        keyfile: keyfile.cloned().map(Locked::new),
+        cipher,
+        password: PasswordSlot {
+            kdf: encrypted_vault.kdf,
+            keyfile: encrypted_vault.keyfile,
+            salt: encrypted_vault.salt,
+            wrapped_key,
+        },
+        recovery: encrypted_vault.recovery,
+    };
+    Ok((data, keys))
+}
+
+/// Decrypts the vault payload with keys already held by the session, without
+/// running the KDF. The file must still carry the session's password slot;
+/// its recovery slot is taken over, since the payload authenticates it.
+pub fn decrypt_vault_with_keys(
+    encrypted_vault: EncryptedVault,
+    keys: &VaultKeys,
+) -> Result<(Vec<u8>, VaultKeys), CryptoError> {
+    check_envelope(&encrypted_vault)?;
+    let password_slot_unchanged = encrypted_vault.kdf == keys.password.kdf
+        && encrypted_vault.keyfile == keys.password.keyfile
+        && encrypted_vault.salt == keys.password.salt
+        && encrypted_vault.wrapped_key.as_ref() == Some(&keys.password.wrapped_key)
+        && encrypted_vault.cipher == keys.cipher;
+    if !password_slot_unchanged {
+        return Err(CryptoError::CredentialsChanged);
+    }
+
+    let aad = encrypted_vault.header_aad()?;
+    let data = open_payload(encrypted_vault.cipher, &keys.data_key, &aad, &encrypted_vault.ciphertext)?;
+
+    let mut keys = keys.clone();
+    keys.recovery = encrypted_vault.recovery;
+    Ok((data, keys))
+}
+
+/// Decrypts an earlier copy of the vault, e.g. a backup, with the session's
+/// data key. Unlike `decrypt_vault_with_keys`, the copy may carry an older
+/// password slot, as changing the password keeps the data key.
+pub fn decrypt_vault_with_data_key(
+    encrypted_vault: &EncryptedVault,
+    keys: &VaultKeys,
 ) -> Result<Vec<u8>, CryptoError> {
-    let salt: [u8; SALT_LEN] = general_purpose::STANDARD
-        .decode(&encrypted_vault.salt)?
-        .try_into()
-        .map_err(|_| CryptoError::Slice)?;
+    check_envelope(encrypted_vault)?;
+    // Without a wrapped data key, the payload was encrypted under a key
+    // derived from the password of the time.
+    if encrypted_vault.wrapped_key.is_none() {
+        return Err(CryptoError::NoDataKey);
+    }
 
-    let derived_key = derive_key(password, &salt)?;
+    let aad = encrypted_vault.header_aad()?;
+    encrypted_vault
+        .cipher
+        .open(&keys.data_key.0, &aad, &encrypted_vault.ciphertext)
+}
 
-    let (nonce_bytes_slice, ciphertext_slice) = encrypted_vault.ciphertext.split_at(NONCE_LEN);
-    let nonce_bytes: [u8; NONCE_LEN] = nonce_bytes_slice
-        .try_into()
-        .map_err(|_| CryptoError::Slice)?;
-    let nonce = RingNonce::assume_unique_for_key(nonce_bytes);
+/// Encrypts a journal entry under the data key with the vault's cipher, which
+/// is returned so the entry can be decrypted after the cipher changes. `aad`
+/// ties the entry to its place in the journal.
+pub fn encrypt_entry(data: &[u8], aad: &[u8], keys: &VaultKeys) -> Result<(Cipher, Vec<u8>), CryptoError> {
+    let sealed = keys.cipher.seal(&keys.data_key.0, aad, data)?;
+    Ok((keys.cipher, sealed))
+}
+
+pub fn decrypt_entry(
+    cipher: Cipher,
+    sealed: &[u8],
+    aad: &[u8],
+    keys: &VaultKeys,
+) -> Result<Vec<u8>, CryptoError> {
+    cipher.open(&keys.data_key.0, aad, sealed)
+}
+
+/// Decrypts the vault payload with its recovery key instead of the password,
+/// then wraps the data key under the new password (and keyfile). The recovery
+/// key stays valid.
+pub fn decrypt_vault_with_recovery_key(
+    encrypted_vault: EncryptedVault,
+    recovery_key: &str,
+    new_password: &MasterPassword,
+    new_keyfile: Option<&KeyfileHash>,
+) -> Result<(Vec<u8>, VaultKeys), CryptoError> {
+    check_envelope(&encrypted_vault)?;
+    let aad = encrypted_vault.header_aad()?;
+    let recovery = encrypted_vault.recovery.ok_or(CryptoError::NoRecoveryKey)?;
+    if encrypted_vault.wrapped_key.is_none() {
+        return Err(CryptoError::NoRecoveryKey);
+    }
+
+    let data_key = Locked::new(recovery.unwrap(&normalize_recovery_key(recovery_key)?)?);
+    let data = open_payload(encrypted_vault.cipher, &data_key, &aad, &encrypted_vault.ciphertext)?;
+
+    let settings = EncryptionSettings {
+        kdf: encrypted_vault.kdf,
+        cipher: encrypted_vault.cipher,
+    };
+    let (slot, password_key) = PasswordSlot::wrap(&data_key, new_password, new_keyfile, &settings)?;
+    let keys = VaultKeys {
+        data_key,
+        password_key: Locked::new(password_key),
+        keyfile: new_keyfile.cloned().map(Locked::new),
+        cipher: settings.cipher,
+        password: slot,
+        recovery: Some(recovery),
+    };
+    Ok((data, keys))
+}
 
-    let unbound_key =
-        UnboundKey::new(&aead::AES_256_GCM, &derived_key.key).map_err(|_| CryptoError::Ring)?;
-    let mut opening_key = OpeningKey::new(unbound_key, OneNonceSequence(Some(nonce)));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIPHERS: [Cipher; 3] = [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305, Cipher::XChaCha20Poly1305];
+
+    /// Cheap settings for each algorithm, so the tests don't spend their time
+    /// in the KDF.
+    fn fast_kdfs() -> [KdfParams; 2] {
+        [
+            KdfParams::Scrypt { log_n: 10, r: 8, p: 1 },
+            KdfParams::Argon2id {
+                memory_kib: 1024,
+                iterations: 1,
+                parallelism: 1,
+            },
+        ]
+    }
+
+    fn password() -> MasterPassword {
+        MasterPassword("correct horse battery staple".to_string())
+    }
+
+    /// Writes the envelope out and reads it back, as saving and loading would.
+    fn reparse(encrypted_vault: &EncryptedVault) -> EncryptedVault {
+        serde_json::from_str(&serde_json::to_string(encrypted_vault).unwrap()).unwrap()
+    }
 
-    let mut in_out = ciphertext_slice.to_vec();
-    let decrypted_data = opening_key
-        .open_in_place(aead::Aad::empty(), &mut in_out)
-        .map_err(|_| CryptoError::Ring)?;
+    #[test]
+    fn round_trips_under_each_kdf_and_cipher() {
+        for kdf in fast_kdfs() {
+            for cipher in CIPHERS {
+                let settings = EncryptionSettings { kdf: kdf.clone(), cipher };
+                let keys = VaultKeys::new(&password(), None, &settings).unwrap();
+                let encrypted_vault = reparse(&encrypt_vault(b"{\"secret\":1}", &keys).unwrap());
+                assert_eq!(encrypted_vault.version, ENVELOPE_VERSION);
 
-    Ok(decrypted_data.to_vec())
+                let (data, reopened) = decrypt_vault(encrypted_vault.clone(), &password(), None).unwrap();
+                assert_eq!(data, b"{\"secret\":1}");
+                assert_eq!(reopened.settings(), settings);
+
+                let wrong = MasterPassword("wrong".to_string());
+                assert!(matches!(
+                    decrypt_vault(encrypted_vault, &wrong, None),
+                    Err(CryptoError::AuthenticationFailed)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn decrypts_legacy_envelopes() {
+        // Version 1 had no header fields besides the salt: fixed scrypt
+        // settings, AES-256-GCM, and the password-derived key encrypting the
+        // payload directly.
+        let salt = random_salt();
+        let key = derive_key(&password(), None, &salt, &KdfParams::legacy_scrypt()).unwrap();
+        let ciphertext = Cipher::Aes256Gcm.seal(&key.key, &[], b"legacy data").unwrap();
+        let legacy: EncryptedVault = serde_json::from_value(serde_json::json!({
+            "salt": general_purpose::STANDARD.encode(salt),
+            "ciphertext": ciphertext,
+        }))
+        .unwrap();
+        assert_eq!(legacy.version, LEGACY_ENVELOPE_VERSION);
+        assert!(legacy.needs_upgrade());
+        assert!(legacy.kdf_needs_upgrade());
+
+        let (data, keys) = decrypt_vault(legacy, &password(), None).unwrap();
+        assert_eq!(data, b"legacy data");
+
+        // Saved again, it gets a wrapped data key and the current format.
+        let upgraded = reparse(&encrypt_vault(&data, &keys).unwrap());
+        assert!(upgraded.wrapped_key.is_some());
+        let (data, _) = decrypt_vault(upgraded, &password(), None).unwrap();
+        assert_eq!(data, b"legacy data");
+    }
+
+    #[test]
+    fn keeps_calibrated_settings_above_the_minimum() {
+        let weakest = KdfParams::Argon2id {
+            memory_kib: CALIBRATION_ARGON2_MEMORY_KIB_RANGE.0,
+            iterations: ARGON2_ITERATIONS,
+            parallelism: ARGON2_PARALLELISM,
+        };
+        assert!(!weakest.is_weaker_than(&weakest.minimum()));
+        assert!(fast_kdfs().iter().all(|kdf| kdf.is_weaker_than(&kdf.minimum())));
+        assert!(!KdfParams::legacy_scrypt().is_weaker_than(&KdfParams::legacy_scrypt().minimum()));
+    }
+
+    #[test]
+    fn rejects_kdf_settings_beyond_the_limits() {
+        let salt = random_salt();
+        for kdf in [
+            KdfParams::Scrypt { log_n: 40, r: 8, p: 1 },
+            KdfParams::Scrypt { log_n: 14, r: 1024, p: 1 },
+            KdfParams::Argon2id {
+                memory_kib: u32::MAX,
+                iterations: 3,
+                parallelism: 1,
+            },
+            KdfParams::Argon2id {
+                memory_kib: 1024,
+                iterations: u32::MAX,
+                parallelism: 1,
+            },
+        ] {
+            assert!(matches!(
+                derive_key(&password(), None, &salt, &kdf),
+                Err(CryptoError::InvalidHeader(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_a_tampered_header() {
+        for cipher in CIPHERS {
+            let settings = EncryptionSettings {
+                kdf: fast_kdfs()[0].clone(),
+                cipher,
+            };
+            let keys = VaultKeys::new(&password(), None, &settings).unwrap();
+            let mut encrypted_vault = reparse(&encrypt_vault(b"data", &keys).unwrap());
+            // Still an authenticated version, but not the one the payload was sealed with.
+            encrypted_vault.version = ENVELOPE_VERSION - 1;
+
+            assert!(matches!(
+                decrypt_vault(encrypted_vault.clone(), &password(), None),
+                Err(CryptoError::HeaderTampered)
+            ));
+            assert!(matches!(
+                decrypt_vault_with_keys(encrypted_vault, &keys),
+                Err(CryptoError::HeaderTampered)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_a_wrong_password() {
+        let settings = EncryptionSettings {
+            kdf: fast_kdfs()[0].clone(),
+            cipher: Cipher::default(),
+        };
+        let keys = VaultKeys::new(&password(), None, &settings).unwrap();
+        let encrypted_vault = reparse(&encrypt_vault(b"data", &keys).unwrap());
+
+        assert!(matches!(
+            decrypt_vault(encrypted_vault, &MasterPassword("wrong".to_string()), None),
+            Err(CryptoError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn round_trips_with_a_keyfile() {
+        let keyfile = KeyfileHash::from_contents(b"keyfile contents");
+        let settings = EncryptionSettings {
+            kdf: fast_kdfs()[0].clone(),
+            cipher: Cipher::default(),
+        };
+        let keys = VaultKeys::new(&password(), Some(&keyfile), &settings).unwrap();
+        let encrypted_vault = reparse(&encrypt_vault(b"data", &keys).unwrap());
+
+        assert!(matches!(
+            decrypt_vault(encrypted_vault.clone(), &password(), None),
+            Err(CryptoError::KeyfileRequired)
+        ));
+        let (data, _) = decrypt_vault(encrypted_vault, &password(), Some(&keyfile)).unwrap();
+        assert_eq!(data, b"data");
+    }
+
+    #[test]
+    fn unlocks_with_the_latest_recovery_key() {
+        let settings = EncryptionSettings {
+            kdf: fast_kdfs()[0].clone(),
+            cipher: Cipher::default(),
+        };
+        let mut keys = VaultKeys::new(&password(), None, &settings).unwrap();
+        let old_recovery_key = keys.set_recovery_key().unwrap();
+        let recovery_key = keys.set_recovery_key().unwrap();
+        let encrypted_vault = reparse(&encrypt_vault(b"data", &keys).unwrap());
+
+        let new_password = MasterPassword("new password".to_string());
+        let (data, new_keys) =
+            decrypt_vault_with_recovery_key(encrypted_vault.clone(), &recovery_key.0, &new_password, None)
+                .unwrap();
+        assert_eq!(data, b"data");
+        // The new password opens the vault once it is saved again.
+        let saved = reparse(&encrypt_vault(&data, &new_keys).unwrap());
+        assert_eq!(decrypt_vault(saved, &new_password, None).unwrap().0, b"data");
+
+        // Generating a new recovery key rotated the data key away from the old one.
+        let old_recovery = decrypt_vault_with_recovery_key(encrypted_vault, &old_recovery_key.0, &new_password, None);
+        assert!(old_recovery.is_err());
+    }
+
+    #[test]
+    fn unlocks_with_enough_recovery_shares() {
+        let settings = EncryptionSettings {
+            kdf: fast_kdfs()[0].clone(),
+            cipher: Cipher::default(),
+        };
+        let mut keys = VaultKeys::new(&password(), None, &settings).unwrap();
+        let shares: Vec<String> = keys
+            .set_recovery_shares(2, 3)
+            .unwrap()
+            .into_iter()
+            .map(|share| share.0.clone())
+            .collect();
+        let encrypted_vault = reparse(&encrypt_vault(b"data", &keys).unwrap());
+
+        let recovery_key = combine_recovery_shares(&shares[1..]).unwrap();
+        let new_password = MasterPassword("new password".to_string());
+        let (data, _) =
+            decrypt_vault_with_recovery_key(encrypted_vault, &recovery_key.0, &new_password, None).unwrap();
+        assert_eq!(data, b"data");
+
+        assert!(combine_recovery_shares(&shares[..1]).is_err());
+        // Shares must carry the current format version.
+        let unversioned: String = shares[0].chars().filter(|c| c.is_ascii_hexdigit()).skip(2).collect();
+        assert!(matches!(
+            combine_recovery_shares(&[unversioned, shares[1].clone()]),
+            Err(CryptoError::InvalidShares(_))
+        ));
+    }
 }
@@ -1,5 +1,5 @@
 //! `defaults.rs`: Defines the default service types for a new vault.
-use crate::models::{ServiceField, ServiceType};
+use crate::models::{ServiceField, ServiceType, Timestamps};
 
 fn create_field(id: &str, key: &str, label: &str, field_type: &str, masked: bool, required: bool) -> ServiceField {
     ServiceField {
@@ -28,6 +28,7 @@ pub fn get_default_service_types() -> Vec<ServiceType> {
                 create_field("d-7", "2fa_key", "2FA Key", "secret", true, false),
                 create_field("d-8", "backup_codes", "Backup Codes", "textarea", true, false),
             ],
+            timestamps: Timestamps::default(),
         },
         // --- Twitter ---
         ServiceType {
@@ -42,6 +43,7 @@ pub fn get_default_service_types() -> Vec<ServiceType> {
                 create_field("t-7", "2fa_key", "2FA Key", "secret", true, false),
                 create_field("t-8", "backup_codes", "Backup Codes", "textarea", true, false),
             ],
+            timestamps: Timestamps::default(),
         },
         // --- Email ---
         ServiceType {
@@ -57,6 +59,7 @@ pub fn get_default_service_types() -> Vec<ServiceType> {
                 create_field("g-6", "recovery_email_access_password", "Recovery Email Access Password", "secret", true, false),
                 create_field("g-7", "2fa_key", "2FA Key", "secret", true, false),
             ],
+            timestamps: Timestamps::default(),
         },
         // --- Proxy ---
         ServiceType {
@@ -66,6 +69,7 @@ pub fn get_default_service_types() -> Vec<ServiceType> {
             fields: vec![
                 create_field("p-1", "proxy_string", "Proxy String", "secret", true, true),
             ],
+            timestamps: Timestamps::default(),
         },
         // --- EVM Wallet ---
         ServiceType {
@@ -77,6 +81,7 @@ pub fn get_default_service_types() -> Vec<ServiceType> {
                 create_field("evm-2", "seed_phrase", "Seed Phrase", "textarea", true, false),
                 create_field("evm-3", "private_key", "Private Key", "textarea", true, false),
             ],
+            timestamps: Timestamps::default(),
         },
         // --- Solana Wallet ---
         ServiceType {
@@ -88,6 +93,7 @@ pub fn get_default_service_types() -> Vec<ServiceType> {
                 create_field("sol-2", "seed_phrase", "Seed Phrase", "textarea", true, false),
                 create_field("sol-3", "private_key", "Private Key", "textarea", true, false),
             ],
+            timestamps: Timestamps::default(),
         },
     ]
 }
@@ -0,0 +1,419 @@
+//! `history.rs`: Rebuilds earlier states of the vault from its backups and
+//! change journals, and compares them with the open vault.
+//!
+//! Each backup is a snapshot of the vault file together with the journal it
+//! had when it was replaced, so it covers the time from when that snapshot was
+//! written until the backup was made. The vault file and its own journal cover
+//! the time since. How far back history reaches depends on the backup settings.
+
+use crate::backups;
+use crate::crypto::VaultKeys;
+use crate::journal::{self, JournalEntry};
+use crate::merge::{Record, RecordKind};
+use crate::models::Vault;
+use crate::operations::Operation;
+use crate::storage::{self, StorageError};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Change {
+    /// The record was added since then.
+    Added,
+    /// The record was deleted since then.
+    Removed,
+    /// The record exists in both, with different contents.
+    Modified,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordChange {
+    pub kind: RecordKind,
+    pub id: String,
+    /// The current label, or the earlier one for removed records.
+    pub label: String,
+    pub change: Change,
+}
+
+/// The vault file or one of its backups, with the journal kept next to it.
+struct Segment {
+    snapshot: PathBuf,
+    /// When the snapshot was replaced by a newer one; `None` for the vault
+    /// file itself.
+    replaced_at: Option<DateTime<Utc>>,
+}
+
+/// Backups first, oldest first, then the vault file.
+fn segments(vault_path: &Path) -> Result<Vec<Segment>, StorageError> {
+    let mut segments: Vec<Segment> = backups::backup_files(vault_path)?
+        .into_iter()
+        .map(|(snapshot, replaced_at)| Segment {
+            snapshot,
+            replaced_at: Some(replaced_at),
+        })
+        .collect();
+    segments.push(Segment {
+        snapshot: vault_path.to_path_buf(),
+        replaced_at: None,
+    });
+    Ok(segments)
+}
+
+/// Every recorded change to the vault the history covers, newest first.
+/// Backups that can't be read with the session's keys, e.g. of a vault that
+/// was replaced by an import, are skipped.
+pub fn list_changes(vault_path: &Path, keys: &VaultKeys) -> Result<Vec<JournalEntry>, StorageError> {
+    let mut segment_entries = Vec::new();
+    // A backup taken before a session's first change has the same snapshot
+    // as the segment after it, whose journal continues the backup's.
+    let mut previous_hash = None;
+    for segment in segments(vault_path)? {
+        let (_, file_hash) = match storage::read_snapshot(&segment.snapshot, keys) {
+            Ok(snapshot) => snapshot,
+            Err(e) if segment.replaced_at.is_some() => {
+                warn!("Skipping {} in the history: {}", segment.snapshot.display(), e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if previous_hash == Some(file_hash) {
+            segment_entries.pop();
+        }
+        previous_hash = Some(file_hash);
+        let journal_path = journal::journal_path(&segment.snapshot);
+        let entries = journal::read_journal(&journal_path, file_hash, keys)?
+            .map(|contents| contents.entries)
+            .unwrap_or_default();
+        segment_entries.push(entries);
+    }
+    let mut changes: Vec<JournalEntry> = segment_entries.into_iter().flatten().collect();
+    changes.reverse();
+    Ok(changes)
+}
+
+/// Rebuilds the vault as it was at `time`: the snapshot that was current then,
+/// with the changes recorded up to that time applied. Fails with
+/// `HistoryUnavailable` if no backup reaches back that far, and with
+/// `HistoryBeforeKeyRotation` if the backup is encrypted under a data key
+/// that was rotated since.
+pub fn vault_at(vault_path: &Path, time: DateTime<Utc>, keys: &VaultKeys) -> Result<Vault, StorageError> {
+    let unavailable = || StorageError::HistoryUnavailable(time.to_rfc3339());
+    let segments = segments(vault_path)?;
+    let index = segments
+        .iter()
+        .position(|segment| match segment.replaced_at {
+            Some(replaced_at) => replaced_at > time,
+            None => true,
+        })
+        .ok_or_else(unavailable)?;
+    let segment = &segments[index];
+
+    let (mut vault, file_hash) = match storage::read_snapshot(&segment.snapshot, keys) {
+        Ok(snapshot) => snapshot,
+        Err(StorageError::Crypto(_)) if segment.replaced_at.is_some() => {
+            return Err(StorageError::HistoryBeforeKeyRotation(time.to_rfc3339()))
+        }
+        Err(e) => return Err(e),
+    };
+    let journal_path = journal::journal_path(&segment.snapshot);
+    let (created_at, entries) = match journal::read_journal(&journal_path, file_hash, keys)? {
+        Some(contents) => (contents.created_at, contents.entries),
+        None => (None, Vec::new()),
+    };
+
+    // The snapshot was written when the one before it was replaced, if that
+    // one is still kept; otherwise its journal may know.
+    let written_at = index
+        .checked_sub(1)
+        .and_then(|previous| segments[previous].replaced_at)
+        .or(created_at);
+    match written_at {
+        Some(written_at) if written_at <= time => {}
+        _ => return Err(unavailable()),
+    }
+
+    for entry in entries {
+        let recorded_at = journal::parse_time(&entry.recorded_at)
+            .ok_or_else(|| StorageError::Journal(format!("invalid time in entry {}", entry.seq)))?;
+        if recorded_at > time {
+            break;
+        }
+        entry.operation.apply(&mut vault)?;
+    }
+    Ok(vault)
+}
+
+/// The records that differ between `then` and `now`, matched by id.
+pub fn compare(then: &Vault, now: &Vault) -> Vec<RecordChange> {
+    let mut changes = Vec::new();
+    compare_records(&then.service_types, &now.service_types, &mut changes);
+    compare_records(&then.services, &now.services, &mut changes);
+    compare_records(&then.accounts, &now.accounts, &mut changes);
+    changes
+}
+
+fn compare_records<T: Record>(then: &[T], now: &[T], changes: &mut Vec<RecordChange>) {
+    let earlier: HashMap<&str, &T> = then.iter().map(|record| (record.id(), record)).collect();
+    for record in now {
+        let change = match earlier.get(record.id()) {
+            None => Change::Added,
+            Some(earlier) if !earlier.same_content(record) => Change::Modified,
+            Some(_) => continue,
+        };
+        changes.push(RecordChange {
+            kind: T::KIND,
+            id: record.id().to_string(),
+            label: record.label().to_string(),
+            change,
+        });
+    }
+    for record in then {
+        if !now.iter().any(|current| current.id() == record.id()) {
+            changes.push(RecordChange {
+                kind: T::KIND,
+                id: record.id().to_string(),
+                label: record.label().to_string(),
+                change: Change::Removed,
+            });
+        }
+    }
+}
+
+/// The operation that puts one record of `now` back the way it was in
+/// `then`: updating, re-adding or moving it to the trash. `None` if neither
+/// has it or it is unchanged. A restored service keeps its field history,
+/// with the values it replaces added as of `restored_at`; a restored account
+/// is only linked to services that still exist.
+pub fn restore_record(
+    then: &Vault,
+    now: &Vault,
+    kind: RecordKind,
+    id: &str,
+    restored_at: &str,
+) -> Option<Operation> {
+    match kind {
+        RecordKind::ServiceType => {
+            let earlier = then.service_types.iter().find(|st| st.id == id);
+            let current = now.service_types.iter().find(|st| st.id == id);
+            match (earlier, current) {
+                (Some(earlier), Some(current)) if earlier.same_content(current) => None,
+                (Some(earlier), Some(_)) => Some(Operation::UpdateServiceType {
+                    service_type: earlier.clone(),
+                }),
+                (Some(earlier), None) => Some(Operation::AddServiceType {
+                    service_type: earlier.clone(),
+                }),
+                (None, Some(_)) => Some(Operation::TrashServiceType {
+                    service_type_id: id.to_string(),
+                    deleted_at: restored_at.to_string(),
+                }),
+                (None, None) => None,
+            }
+        }
+        RecordKind::Service => {
+            let earlier = then.services.iter().find(|s| s.id == id);
+            let current = now.services.iter().find(|s| s.id == id);
+            match (earlier, current) {
+                (Some(earlier), Some(current)) if earlier.same_content(current) => None,
+                (Some(earlier), Some(current)) => Some(Operation::UpdateService {
+                    service: earlier.clone().with_history_from(current, restored_at),
+                }),
+                (Some(earlier), None) => Some(Operation::AddService {
+                    service: earlier.clone(),
+                    account_id: None,
+                }),
+                (None, Some(_)) => Some(Operation::TrashServices {
+                    service_ids: vec![id.to_string()],
+                    deleted_at: restored_at.to_string(),
+                }),
+                (None, None) => None,
+            }
+        }
+        RecordKind::Account => {
+            let earlier = then.accounts.iter().find(|a| a.id == id).map(|earlier| {
+                let mut earlier = earlier.clone();
+                earlier
+                    .linked_services
+                    .retain(|service_id| now.services.iter().any(|s| &s.id == service_id));
+                earlier
+            });
+            let current = now.accounts.iter().find(|a| a.id == id);
+            match (earlier, current) {
+                (Some(earlier), Some(current)) if earlier.same_content(current) => None,
+                (Some(earlier), Some(_)) => Some(Operation::UpdateAccount { account: earlier }),
+                (Some(earlier), None) => Some(Operation::AddAccount { account: earlier }),
+                (None, Some(_)) => Some(Operation::TrashAccount {
+                    account_id: id.to_string(),
+                    deleted_at: restored_at.to_string(),
+                }),
+                (None, None) => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Cipher, EncryptionSettings, KdfParams, MasterPassword};
+    use crate::journal::Journal;
+    use crate::models::{Account, BackupSettings, Settings, Timestamps};
+    use std::fs;
+
+    fn keys() -> VaultKeys {
+        let settings = EncryptionSettings {
+            kdf: KdfParams::Scrypt { log_n: 10, r: 8, p: 1 },
+            cipher: Cipher::default(),
+        };
+        VaultKeys::new(&MasterPassword("password".to_string()), None, &settings).unwrap()
+    }
+
+    fn account(id: &str) -> Account {
+        Account {
+            id: id.to_string(),
+            label: id.to_string(),
+            notes: String::new(),
+            tags: Vec::new(),
+            linked_services: Vec::new(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn vault() -> Vault {
+        Vault {
+            version: crate::migrations::CURRENT_VAULT_VERSION.to_string(),
+            service_types: Vec::new(),
+            services: Vec::new(),
+            accounts: vec![account("a1")],
+            settings: Settings {
+                auto_lock_minutes: 5,
+                backup: BackupSettings::default(),
+                trash_retention_days: 30,
+            },
+            trash: Vec::new(),
+        }
+    }
+
+    /// A fresh directory for one test's vault files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("accman-history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn lists_changes_kept_in_a_session_backup_once() {
+        let dir = test_dir("session-backup");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (_, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let (mut journal, _) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+        journal
+            .append(&vault_path, &Operation::AddAccount { account: account("a2") }, &keys)
+            .unwrap();
+
+        // The next session backs the vault up before its first change.
+        backups::back_up(&vault_path).unwrap();
+        journal
+            .append(&vault_path, &Operation::AddAccount { account: account("a3") }, &keys)
+            .unwrap();
+
+        let changes = list_changes(&vault_path, &keys).unwrap();
+        assert_eq!(changes.iter().map(|e| e.seq).collect::<Vec<_>>(), [2, 1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_backups_under_a_replaced_key() {
+        let dir = test_dir("rotated");
+        let vault_path = dir.join("vault.json");
+        storage::export_vault(&vault_path, &vault(), &keys()).unwrap();
+        let before = Utc::now() - chrono::Duration::seconds(1);
+        backups::back_up(&vault_path).unwrap();
+
+        // The vault is written again under a new data key.
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        assert!(matches!(
+            vault_at(&vault_path, before, &keys),
+            Err(StorageError::HistoryBeforeKeyRotation(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_the_vault_as_it_was() {
+        let dir = test_dir("vault-at");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (_, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let before = Utc::now() - chrono::Duration::seconds(1);
+        let mut journal = Journal::create(&vault_path, snapshot).unwrap();
+        journal
+            .append(&vault_path, &Operation::AddAccount { account: account("a2") }, &keys)
+            .unwrap();
+        let between = Utc::now();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let deletion = Operation::DeleteAccount {
+            account_id: "a1".to_string(),
+        };
+        journal.append(&vault_path, &deletion, &keys).unwrap();
+
+        let account_ids = |vault: &Vault| -> Vec<String> {
+            vault.accounts.iter().map(|a| a.id.clone()).collect()
+        };
+        assert_eq!(account_ids(&vault_at(&vault_path, between, &keys).unwrap()), ["a1", "a2"]);
+        assert_eq!(account_ids(&vault_at(&vault_path, Utc::now(), &keys).unwrap()), ["a2"]);
+        // Nothing was recorded before the snapshot was written.
+        assert!(matches!(
+            vault_at(&vault_path, before, &keys),
+            Err(StorageError::HistoryUnavailable(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restores_one_record_the_way_it_was() {
+        let restored_at = "2024-03-01T00:00:00+00:00";
+        let mut then = vault();
+        then.accounts.push(account("a2"));
+        let mut now = vault();
+        now.accounts[0].label = "Renamed".to_string();
+        now.accounts.push(account("a3"));
+
+        let changes = compare(&then, &now);
+        let change = |id: &str| changes.iter().find(|c| c.id == id).map(|c| c.change);
+        assert_eq!(change("a1"), Some(Change::Modified));
+        assert_eq!(change("a2"), Some(Change::Removed));
+        assert_eq!(change("a3"), Some(Change::Added));
+
+        let restore = |id: &str| restore_record(&then, &now, RecordKind::Account, id, restored_at);
+        assert!(matches!(
+            restore("a1"),
+            Some(Operation::UpdateAccount { account }) if account.label == "a1"
+        ));
+        assert!(matches!(restore("a2"), Some(Operation::AddAccount { account }) if account.id == "a2"));
+        assert!(matches!(
+            restore("a3"),
+            Some(Operation::TrashAccount { account_id, .. }) if account_id == "a3"
+        ));
+        assert!(restore("missing").is_none());
+
+        // A restored account only links to services that still exist.
+        then.accounts[0].linked_services = vec!["gone".to_string()];
+        let Some(Operation::UpdateAccount { account }) =
+            restore_record(&then, &now, RecordKind::Account, "a1", restored_at)
+        else {
+            panic!("expected an update");
+        };
+        assert!(account.linked_services.is_empty());
+    }
+}
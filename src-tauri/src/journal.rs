@@ -0,0 +1,566 @@
+//! `journal.rs`: Append-only, encrypted log of the operations made since the
+//! vault file was last written in full.
+//!
+//! The vault file is a snapshot; each change is appended to the journal next
+//! to it as its own encrypted entry instead of rewriting the snapshot. Loading
+//! applies the entries on top of the snapshot. Every full save of the vault
+//! compacts the journal: the new snapshot contains all changes so far, and
+//! the journal starts over, tied to it by the snapshot's hash.
+
+use crate::crypto::{self, Cipher, VaultKeys};
+use crate::operations::Operation;
+use crate::storage::{self, FileHash, StorageError};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const JOURNAL_VERSION: u32 = 1;
+/// The vault is compacted once its journal has this many entries, so loading
+/// never has to replay more than that.
+pub const COMPACT_AFTER_ENTRIES: usize = 500;
+
+/// First line of the journal file.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JournalHeader {
+    version: u32,
+    /// Hash of the snapshot the entries apply to, in hex.
+    snapshot: String,
+    /// When the snapshot was written, as an RFC 3339 timestamp. Unknown for
+    /// journals started when an existing vault was first opened.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+}
+
+/// One line per entry after the header.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedEntry {
+    seq: u64,
+    cipher: Cipher,
+    /// Nonce, ciphertext and tag, base64-encoded.
+    sealed: String,
+}
+
+/// An operation as recorded in the journal.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntry {
+    pub seq: u64,
+    /// RFC 3339 timestamp.
+    pub recorded_at: String,
+    pub operation: Operation,
+}
+
+/// The encrypted part of an entry.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryPayload {
+    recorded_at: String,
+    operation: Operation,
+}
+
+/// The journal of one snapshot, as this session last read or wrote it. Its
+/// snapshot hash and length tell whether someone else changed the vault
+/// files since.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+    snapshot: FileHash,
+    /// The snapshot file's metadata when it was last seen with that hash.
+    snapshot_stamp: Option<FileStamp>,
+    len: u64,
+    entries: usize,
+    /// Whether the snapshot and its journal are backed up as they were when
+    /// this session took them over: true for a snapshot it wrote itself, as
+    /// a full save backs up the file it replaces.
+    backed_up: bool,
+}
+
+/// Length and modification time of a file, to tell cheaply whether it was
+/// written since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    /// Changes whenever the file is replaced through a rename, even within
+    /// the resolution of the modification time.
+    file_id: Option<u64>,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<FileStamp> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(FileStamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            file_id: file_id(&metadata),
+        })
+    }
+
+    /// The stamp of the snapshot file, if it still hashes to `snapshot`. Taken
+    /// before hashing, so a write in between shows up as a changed file.
+    fn of_snapshot(vault_path: &Path, snapshot: FileHash) -> Option<FileStamp> {
+        let stamp = FileStamp::of(vault_path)?;
+        let contents = fs::read(vault_path).ok()?;
+        (FileHash::of(&contents) == snapshot).then_some(stamp)
+    }
+}
+
+impl Journal {
+    /// Starts an empty journal for a snapshot that was just written,
+    /// replacing any previous journal.
+    pub fn create(vault_path: &Path, snapshot: FileHash) -> Result<Journal, StorageError> {
+        let mut journal = Journal::start(vault_path, snapshot, Some(Utc::now().to_rfc3339()))?;
+        journal.backed_up = true;
+        Ok(journal)
+    }
+
+    fn start(
+        vault_path: &Path,
+        snapshot: FileHash,
+        created_at: Option<String>,
+    ) -> Result<Journal, StorageError> {
+        let path = journal_path(vault_path);
+        let mut header = serde_json::to_vec(&JournalHeader {
+            version: JOURNAL_VERSION,
+            snapshot: snapshot.to_hex(),
+            created_at,
+        })?;
+        header.push(b'\n');
+        storage::write_atomically(&path, &header)?;
+        Ok(Journal {
+            path,
+            snapshot,
+            snapshot_stamp: FileStamp::of_snapshot(vault_path, snapshot),
+            len: header.len() as u64,
+            entries: 0,
+            backed_up: false,
+        })
+    }
+
+    /// Reads and decrypts the entries recorded for `snapshot`. A journal left
+    /// from another snapshot has nothing to add to this one and is replaced.
+    pub fn open(
+        vault_path: &Path,
+        snapshot: FileHash,
+        keys: &VaultKeys,
+    ) -> Result<(Journal, Vec<JournalEntry>), StorageError> {
+        let path = journal_path(vault_path);
+        let contents = match read_journal(&path, snapshot, keys)? {
+            Some(contents) => contents,
+            None => {
+                if path.exists() {
+                    warn!("Discarding a journal that doesn't belong to the vault file");
+                }
+                // When the snapshot was written is unknown here.
+                return Ok((Journal::start(vault_path, snapshot, None)?, Vec::new()));
+            }
+        };
+
+        if contents.len < contents.file_len {
+            OpenOptions::new().write(true).open(&path)?.set_len(contents.len)?;
+        }
+        if !contents.entries.is_empty() {
+            info!("Read {} journal entries", contents.entries.len());
+        }
+        let journal = Journal {
+            path,
+            snapshot,
+            snapshot_stamp: FileStamp::of_snapshot(vault_path, snapshot),
+            len: contents.len,
+            entries: contents.entries.len(),
+            backed_up: false,
+        };
+        Ok((journal, contents.entries))
+    }
+
+    /// Encrypts `operation` and appends it. Fails if the snapshot or the
+    /// journal changed on disk since this session last read or wrote them.
+    pub fn append(
+        &mut self,
+        vault_path: &Path,
+        operation: &Operation,
+        keys: &VaultKeys,
+    ) -> Result<JournalEntry, StorageError> {
+        self.check_unchanged(vault_path)?;
+
+        let entry = JournalEntry {
+            seq: self.entries as u64 + 1,
+            recorded_at: Utc::now().to_rfc3339(),
+            operation: operation.clone(),
+        };
+        let payload = serde_json::to_vec(&EntryPayload {
+            recorded_at: entry.recorded_at.clone(),
+            operation: entry.operation.clone(),
+        })?;
+        let (cipher, sealed) = crypto::encrypt_entry(&payload, &entry_aad(self.snapshot, entry.seq), keys)?;
+        let mut line = serde_json::to_vec(&EncryptedEntry {
+            seq: entry.seq,
+            cipher,
+            sealed: general_purpose::STANDARD.encode(sealed),
+        })?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        self.len += line.len() as u64;
+        self.entries += 1;
+        Ok(entry)
+    }
+
+    /// Fails with `ChangedOnDisk` unless the snapshot and the journal are as
+    /// this session last read or wrote them. The snapshot is only hashed again
+    /// if its length or modification time changed, e.g. after a sync client
+    /// touched it.
+    pub fn check_unchanged(&self, vault_path: &Path) -> Result<(), StorageError> {
+        // A deleted file counts as changed too.
+        let snapshot_unchanged = match (FileStamp::of(vault_path), self.snapshot_stamp) {
+            (Some(stamp), Some(known)) if stamp == known => true,
+            _ => fs::read(vault_path).is_ok_and(|contents| FileHash::of(&contents) == self.snapshot),
+        };
+        let journal_len = fs::metadata(&self.path).ok().map(|metadata| metadata.len());
+        if !snapshot_unchanged || journal_len != Some(self.len) {
+            return Err(StorageError::ChangedOnDisk);
+        }
+        Ok(())
+    }
+
+    /// Number of entries since the last compaction.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.entries >= COMPACT_AFTER_ENTRIES
+    }
+
+    pub fn backed_up(&self) -> bool {
+        self.backed_up
+    }
+
+    pub fn set_backed_up(&mut self) {
+        self.backed_up = true;
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// What a journal file holds for one snapshot.
+pub struct JournalContents {
+    /// When the snapshot was written, if known.
+    pub created_at: Option<DateTime<Utc>>,
+    pub entries: Vec<JournalEntry>,
+    /// Length of the complete entries; anything after them was cut short.
+    len: u64,
+    file_len: u64,
+}
+
+/// Reads the journal at `path` without changing it, e.g. one kept with a
+/// backup. Returns `None` if there is no journal for `snapshot` there, and
+/// fails for a journal written by a newer version, which must not be replaced.
+pub fn read_journal(
+    path: &Path,
+    snapshot: FileHash,
+    keys: &VaultKeys,
+) -> Result<Option<JournalContents>, StorageError> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut lines = contents.split_inclusive(|byte| *byte == b'\n');
+    let header = match lines
+        .next()
+        .and_then(|line| serde_json::from_slice::<JournalHeader>(line).ok())
+    {
+        Some(header) if header.version > JOURNAL_VERSION => {
+            return Err(StorageError::Journal(format!(
+                "the journal was written by a newer version of AccMan (format {}); update the app to open it",
+                header.version
+            )));
+        }
+        Some(header) if header.snapshot == snapshot.to_hex() => header,
+        _ => return Ok(None),
+    };
+    let mut len = contents.len() - lines.clone().map(<[u8]>::len).sum::<usize>();
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let entry = match serde_json::from_slice::<EncryptedEntry>(line) {
+            Ok(entry) if line.ends_with(b"\n") => entry,
+            // Only the last entry can be cut short, by a crash while appending it.
+            _ => {
+                warn!("Dropping an incomplete entry at the end of the journal");
+                break;
+            }
+        };
+        let expected_seq = entries.len() as u64 + 1;
+        if entry.seq != expected_seq {
+            return Err(StorageError::Journal(format!(
+                "expected entry {} but found {}",
+                expected_seq, entry.seq
+            )));
+        }
+        entries.push(decrypt_entry(&entry, snapshot, keys)?);
+        len += line.len();
+    }
+
+    Ok(Some(JournalContents {
+        created_at: header.created_at.as_deref().and_then(parse_time),
+        entries,
+        len: len as u64,
+        file_len: contents.len() as u64,
+    }))
+}
+
+/// Parses an RFC 3339 timestamp as recorded in the journal.
+pub fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// The journal sits next to the vault file, or the backup it was kept with.
+pub fn journal_path(vault_path: &Path) -> PathBuf {
+    let mut file_name = vault_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".journal");
+    vault_path.with_file_name(file_name)
+}
+
+/// Binds an entry to its snapshot and position, so entries can't be moved
+/// between journals or reordered without failing to decrypt.
+fn entry_aad(snapshot: FileHash, seq: u64) -> Vec<u8> {
+    format!("{}/{}", snapshot.to_hex(), seq).into_bytes()
+}
+
+fn decrypt_entry(
+    entry: &EncryptedEntry,
+    snapshot: FileHash,
+    keys: &VaultKeys,
+) -> Result<JournalEntry, StorageError> {
+    let sealed = general_purpose::STANDARD
+        .decode(&entry.sealed)
+        .map_err(|e| StorageError::Journal(e.to_string()))?;
+    let payload = crypto::decrypt_entry(entry.cipher, &sealed, &entry_aad(snapshot, entry.seq), keys)?;
+    let payload: EntryPayload = serde_json::from_slice(&payload)?;
+    Ok(JournalEntry {
+        seq: entry.seq,
+        recorded_at: payload.recorded_at,
+        operation: payload.operation,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{EncryptionSettings, KdfParams, MasterPassword};
+    use crate::models::{Account, BackupSettings, Settings, Timestamps, Vault};
+
+    fn keys() -> VaultKeys {
+        let settings = EncryptionSettings {
+            kdf: KdfParams::Scrypt { log_n: 10, r: 8, p: 1 },
+            cipher: Cipher::default(),
+        };
+        VaultKeys::new(&MasterPassword("password".to_string()), None, &settings).unwrap()
+    }
+
+    fn account(id: &str) -> Account {
+        Account {
+            id: id.to_string(),
+            label: id.to_string(),
+            notes: String::new(),
+            tags: Vec::new(),
+            linked_services: Vec::new(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn vault() -> Vault {
+        Vault {
+            version: crate::migrations::CURRENT_VAULT_VERSION.to_string(),
+            service_types: Vec::new(),
+            services: Vec::new(),
+            accounts: vec![account("a1")],
+            settings: Settings {
+                auto_lock_minutes: 5,
+                backup: BackupSettings::default(),
+                trash_retention_days: 30,
+            },
+            trash: Vec::new(),
+        }
+    }
+
+    /// A fresh directory for one test's vault files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("accman-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn account_ids(vault: &Vault) -> Vec<&str> {
+        vault.accounts.iter().map(|a| a.id.as_str()).collect()
+    }
+
+    #[test]
+    fn replays_appended_entries() {
+        let dir = test_dir("replay");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (_, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+
+        let (mut journal, entries) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+        assert!(entries.is_empty());
+        let operations = [
+            Operation::AddAccount { account: account("a2") },
+            Operation::DeleteAccount {
+                account_id: "a1".to_string(),
+            },
+        ];
+        for operation in &operations {
+            journal.append(&vault_path, operation, &keys).unwrap();
+        }
+
+        let (mut replayed, _) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let (reopened, entries) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+        assert_eq!(reopened.entries(), 2);
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), [1, 2]);
+        for entry in entries {
+            entry.operation.apply(&mut replayed).unwrap();
+        }
+        assert_eq!(account_ids(&replayed), ["a2"]);
+
+        // Entries can't be read with another vault's keys.
+        assert!(Journal::open(&vault_path, snapshot, &self::keys()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_another_vault_file_with_its_journal() {
+        let dir = test_dir("exported");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (_, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let (mut journal, _) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+        journal
+            .append(&vault_path, &Operation::AddAccount { account: account("a2") }, &keys)
+            .unwrap();
+        let written = fs::read(journal_path(&vault_path)).unwrap();
+
+        let password = MasterPassword("password".to_string());
+        let imported = storage::read_exported_vault(&vault_path, &password, None).unwrap();
+        assert_eq!(account_ids(&imported), ["a1", "a2"]);
+        // Reading someone else's file leaves its journal as it was.
+        assert_eq!(fs::read(journal_path(&vault_path)).unwrap(), written);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_an_entry_cut_short() {
+        let dir = test_dir("truncated");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (_, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let (mut journal, _) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+        journal
+            .append(&vault_path, &Operation::AddAccount { account: account("a2") }, &keys)
+            .unwrap();
+
+        let path = journal_path(&vault_path);
+        let complete_len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"seq\":2,\"cip").unwrap();
+
+        let (_, entries) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compacts_into_a_new_snapshot() {
+        let dir = test_dir("compaction");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (mut current, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let (mut journal, _) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+        let operation = Operation::AddAccount { account: account("a2") };
+        journal.append(&vault_path, &operation, &keys).unwrap();
+        operation.apply(&mut current).unwrap();
+
+        // A full save writes the changes into the snapshot and starts over.
+        storage::export_vault(&vault_path, &current, &keys).unwrap();
+        let (compacted, new_snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let journal = Journal::create(&vault_path, new_snapshot).unwrap();
+        assert_eq!(journal.entries(), 0);
+        assert!(!journal.needs_compaction());
+        assert_eq!(account_ids(&compacted), ["a1", "a2"]);
+
+        let (_, entries) = Journal::open(&vault_path, new_snapshot, &keys).unwrap();
+        assert!(entries.is_empty());
+        // The old snapshot's entries are gone with its journal.
+        let contents = read_journal(&journal_path(&vault_path), snapshot, &keys).unwrap();
+        assert!(contents.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_appends_after_outside_changes() {
+        let dir = test_dir("outside");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (_, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        let (mut journal, _) = Journal::open(&vault_path, snapshot, &keys).unwrap();
+
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let result = journal.append(&vault_path, &Operation::AddAccount { account: account("a2") }, &keys);
+        assert!(matches!(result, Err(StorageError::ChangedOnDisk)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_journals_from_newer_versions() {
+        let dir = test_dir("newer");
+        let vault_path = dir.join("vault.json");
+        let keys = keys();
+        storage::export_vault(&vault_path, &vault(), &keys).unwrap();
+        let (_, snapshot) = storage::read_snapshot(&vault_path, &keys).unwrap();
+        Journal::open(&vault_path, snapshot, &keys).unwrap();
+
+        let path = journal_path(&vault_path);
+        let mut header: JournalHeader = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        header.version = JOURNAL_VERSION + 1;
+        let written = serde_json::to_vec(&header).unwrap();
+        fs::write(&path, &written).unwrap();
+
+        assert!(matches!(
+            Journal::open(&vault_path, snapshot, &keys),
+            Err(StorageError::Journal(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), written);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,17 +1,44 @@
 use std::fs;
-use tauri::{path::BaseDirectory, Manager};
+use tauri::Manager;
 
+pub mod backups;
 pub mod commands;
 pub mod crypto;
+pub mod history;
+pub mod journal;
+pub mod merge;
+pub mod migrations;
 pub mod models;
+pub mod operations;
+pub mod query;
+pub mod registry;
 pub mod storage;
+pub mod undo;
+pub mod vault_lock;
 pub mod defaults;
 
 use commands::{AppState, SessionState};
+use registry::{RegistryState, VaultRegistry};
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 
-pub struct StoragePath(pub PathBuf);
+/// Location of the active vault file. Opening another vault swaps it, so
+/// commands read it when they run instead of keeping a copy.
+pub struct StoragePath(RwLock<PathBuf>);
+
+impl StoragePath {
+    pub fn new(path: PathBuf) -> Self {
+        StoragePath(RwLock::new(path))
+    }
+
+    pub fn get(&self) -> PathBuf {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, path: PathBuf) {
+        *self.0.write().unwrap() = path;
+    }
+}
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
@@ -21,17 +48,29 @@ pub fn run() {
         .setup(|app| {
             #[cfg(desktop)]
             app.handle().plugin(tauri_plugin_updater::Builder::new().build())?;
-            let path = app
-                .path()
-                .resolve("vault.json", BaseDirectory::AppLocalData)?;
-
-            if let Some(parent) = path.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent)?;
-                }
+            let portable_dir = registry::portable_data_dir();
+            let portable = portable_dir.is_some();
+            let app_data_dir = match portable_dir {
+                Some(dir) => dir,
+                None => app.path().app_local_data_dir()?,
+            };
+            if !app_data_dir.exists() {
+                fs::create_dir_all(&app_data_dir)?;
             }
 
-            app.manage(StoragePath(path));
+            let registry = VaultRegistry::load(&app_data_dir)?;
+            registry.save(&app_data_dir)?;
+            let path = registry
+                .active()
+                .map(|profile| profile.path.clone())
+                .expect("the loaded registry always has an active vault");
+
+            app.manage(StoragePath::new(path));
+            app.manage(RegistryState {
+                app_data_dir,
+                portable,
+                registry: Mutex::new(registry),
+            });
             app.manage(AppState(Mutex::new(None)));
             app.manage(SessionState::default());
 
@@ -46,11 +85,56 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             commands::vault_exists,
+            commands::vault_requires_keyfile,
             commands::get_default_service_types_list,
+            commands::calibrate_kdf,
             commands::create_vault,
             commands::unlock_vault,
             commands::lock_vault,
+            commands::break_vault_lock,
+            commands::recover_vault,
+            commands::recover_vault_with_shares,
+            commands::generate_recovery_key,
+            commands::generate_recovery_shares,
+            commands::list_backups,
+            commands::restore_backup,
+            commands::reload_vault,
             commands::get_vault,
+            // Export / Import
+            commands::export_vault_backup,
+            commands::import_vault_backup,
+            // Merge
+            commands::preview_vault_merge,
+            commands::apply_vault_merge,
+            commands::cancel_vault_merge,
+            // History
+            commands::list_vault_history,
+            commands::preview_vault_at,
+            commands::restore_history_record,
+            commands::restore_vault_at,
+            // Record queries
+            commands::list_accounts,
+            commands::list_services,
+            commands::list_service_types,
+            commands::mark_record_used,
+            // Undo
+            commands::undo,
+            commands::redo,
+            commands::get_undo_state,
+            // Trash
+            commands::list_trash,
+            commands::restore_from_trash,
+            commands::purge_trash,
+            commands::empty_trash,
+            // Vault registry
+            commands::list_vaults,
+            commands::is_portable_mode,
+            commands::create_vault_profile,
+            commands::open_vault_profile,
+            commands::open_vault_file,
+            commands::switch_vault,
+            commands::rename_vault,
+            commands::forget_vault,
             // Settings
             commands::update_settings,
             commands::change_master_password,
@@ -62,6 +146,8 @@ pub fn run() {
             commands::add_service,
             commands::add_services,
             commands::update_service,
+            commands::get_service_field_history,
+            commands::restore_field_value,
             commands::delete_service,
             commands::delete_services,
             // Account
@@ -71,6 +157,12 @@ pub fn run() {
             commands::link_services_to_account,
             commands::bulk_create_accounts
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                // Release the vault lock so the next start doesn't see it as stale.
+                app.state::<SessionState>().vault_lock.lock().unwrap().take();
+            }
+        });
 }
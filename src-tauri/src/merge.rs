@@ -0,0 +1,667 @@
+//! `merge.rs`: Folds the records of another vault into the open one.
+
+use crate::models::{Account, Service, ServiceType, Timestamped, Vault};
+use crate::operations::TrashKey;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordKind {
+    ServiceType,
+    Service,
+    Account,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MatchStatus {
+    /// Only the other vault has the record.
+    New,
+    /// Both vaults have the record with the same contents.
+    Identical,
+    /// Both vaults have the record, with different contents.
+    Conflict,
+    /// The open vault has the record in its trash. It is left there, to be
+    /// restored from the trash if wanted.
+    InTrash,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeItem {
+    pub kind: RecordKind,
+    pub their_id: String,
+    /// The matching record of the open vault or its trash, if there is one.
+    pub my_id: Option<String>,
+    pub label: String,
+    /// No record of the open vault has the same id, but one has the same label.
+    pub matched_by_label: bool,
+    pub status: MatchStatus,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergePreview {
+    pub items: Vec<MergeItem>,
+    pub new: usize,
+    pub identical: usize,
+    pub conflicts: usize,
+    pub in_trash: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Resolution {
+    KeepMine,
+    KeepTheirs,
+    /// Their record is added next to mine under a new id.
+    KeepBoth,
+}
+
+/// How to resolve one conflicting record. Conflicts without a decision keep mine.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeDecision {
+    pub kind: RecordKind,
+    pub their_id: String,
+    pub resolution: Resolution,
+}
+
+/// What an import or merge changed in the vault.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportCounts {
+    pub service_types_added: usize,
+    pub services_added: usize,
+    pub accounts_added: usize,
+    /// Records of the open vault replaced by theirs.
+    pub replaced: usize,
+    /// Records that were identical, in my trash, or where mine were kept.
+    pub skipped: usize,
+}
+
+impl ImportCounts {
+    /// Counts for a vault that replaces the current one entirely.
+    pub fn of(vault: &Vault) -> Self {
+        ImportCounts {
+            service_types_added: vault.service_types.len(),
+            services_added: vault.services.len(),
+            accounts_added: vault.accounts.len(),
+            ..Default::default()
+        }
+    }
+}
+
+// --- Public Functions ---
+
+/// Matches the records of `theirs` against `mine` and reports which are new,
+/// identical, conflicting or in my trash. Nothing is changed.
+pub fn preview_merge(mine: &Vault, theirs: &Vault) -> MergePreview {
+    let matches = Matches::find(mine, theirs);
+    let mut remapped = theirs.clone();
+    remap_ids(&mut remapped, &matches.id_maps(|_, _| false));
+
+    let mut preview = MergePreview::default();
+    compare_records(&mut preview, &mine.service_types, &theirs.service_types, &remapped.service_types, &matches);
+    compare_records(&mut preview, &mine.services, &theirs.services, &remapped.services, &matches);
+    compare_records(&mut preview, &mine.accounts, &theirs.accounts, &remapped.accounts, &matches);
+    preview
+}
+
+/// Merges `theirs` into `mine` following `decisions`. References between
+/// records, such as an account's `linked_services`, are remapped to the ids
+/// the records end up with.
+pub fn merge_vaults(mine: &mut Vault, theirs: Vault, decisions: &[MergeDecision]) -> ImportCounts {
+    let preview = preview_merge(mine, &theirs);
+    let conflicts: HashSet<(RecordKind, &str)> = preview
+        .items
+        .iter()
+        .filter(|item| item.status == MatchStatus::Conflict)
+        .map(|item| (item.kind, item.their_id.as_str()))
+        .collect();
+    // Only conflicts are up for decision; identical records always keep mine.
+    let resolve = |kind: RecordKind, their_id: &str| {
+        if !conflicts.contains(&(kind, their_id)) {
+            return Resolution::KeepMine;
+        }
+        decisions
+            .iter()
+            .find(|decision| decision.kind == kind && decision.their_id == their_id)
+            .map_or(Resolution::KeepMine, |decision| decision.resolution)
+    };
+
+    let matches = Matches::find(mine, &theirs);
+    let their_ids = TheirIds::of(&theirs);
+    let mut theirs = theirs;
+    remap_ids(
+        &mut theirs,
+        &matches.id_maps(|kind, their_id| resolve(kind, their_id) == Resolution::KeepBoth),
+    );
+
+    let mut counts = ImportCounts::default();
+    counts.service_types_added = merge_records(
+        &mut mine.service_types,
+        theirs.service_types,
+        &their_ids.service_types,
+        &matches,
+        &resolve,
+        &mut counts,
+    );
+    counts.services_added = merge_records(
+        &mut mine.services,
+        theirs.services,
+        &their_ids.services,
+        &matches,
+        &resolve,
+        &mut counts,
+    );
+    counts.accounts_added = merge_records(
+        &mut mine.accounts,
+        theirs.accounts,
+        &their_ids.accounts,
+        &matches,
+        &resolve,
+        &mut counts,
+    );
+    counts
+}
+
+// --- Matching ---
+
+pub(crate) trait Record: Clone + PartialEq + Timestamped {
+    const KIND: RecordKind;
+    fn id(&self) -> &str;
+    fn label(&self) -> &str;
+
+    /// Whether `other` holds the same data, no matter when either was
+    /// created, changed or last used.
+    fn same_content(&self, other: &Self) -> bool {
+        let mut other = other.clone();
+        *other.timestamps_mut() = self.timestamps().clone();
+        *self == other
+    }
+}
+
+impl Record for ServiceType {
+    const KIND: RecordKind = RecordKind::ServiceType;
+    fn id(&self) -> &str {
+        &self.id
+    }
+    fn label(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Record for Service {
+    const KIND: RecordKind = RecordKind::Service;
+    fn id(&self) -> &str {
+        &self.id
+    }
+    fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+impl Record for Account {
+    const KIND: RecordKind = RecordKind::Account;
+    fn id(&self) -> &str {
+        &self.id
+    }
+    fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+struct Match {
+    my_id: String,
+    by_label: bool,
+}
+
+type IdMap = HashMap<String, String>;
+
+/// Their record ids mapped to the matching records of mine, per kind.
+/// Records whose id is in my trash match nothing.
+struct Matches {
+    service_types: HashMap<String, Match>,
+    services: HashMap<String, Match>,
+    accounts: HashMap<String, Match>,
+    trashed: HashSet<(RecordKind, String)>,
+}
+
+impl Matches {
+    fn find(mine: &Vault, theirs: &Vault) -> Self {
+        let trashed: HashSet<(RecordKind, String)> = mine
+            .trash
+            .iter()
+            .map(|item| {
+                let key = TrashKey::of(item);
+                (key.kind, key.id)
+            })
+            .collect();
+        let service_types = match_records(
+            &mine.service_types,
+            &theirs.service_types,
+            &trashed,
+            label_key,
+            label_key,
+        );
+
+        // Services only match by label within the same (matched) service type.
+        let services = match_records(
+            &mine.services,
+            &theirs.services,
+            &trashed,
+            |s| format!("{}/{}", s.service_type_id, label_key(s)),
+            |s| {
+                let service_type_id = service_types
+                    .get(&s.service_type_id)
+                    .map_or(s.service_type_id.as_str(), |m| m.my_id.as_str());
+                format!("{}/{}", service_type_id, label_key(s))
+            },
+        );
+
+        let accounts = match_records(&mine.accounts, &theirs.accounts, &trashed, label_key, label_key);
+
+        Matches {
+            service_types,
+            services,
+            accounts,
+            trashed,
+        }
+    }
+
+    fn is_trashed(&self, kind: RecordKind, their_id: &str) -> bool {
+        self.trashed.contains(&(kind, their_id.to_string()))
+    }
+
+    fn of_kind(&self, kind: RecordKind) -> &HashMap<String, Match> {
+        match kind {
+            RecordKind::ServiceType => &self.service_types,
+            RecordKind::Service => &self.services,
+            RecordKind::Account => &self.accounts,
+        }
+    }
+
+    /// The id each matched record of theirs takes on: mine, or a new one for
+    /// records kept next to mine.
+    fn id_maps(&self, keep_both: impl Fn(RecordKind, &str) -> bool) -> IdMaps {
+        let map = |kind: RecordKind| -> IdMap {
+            self.of_kind(kind)
+                .iter()
+                .map(|(their_id, m)| {
+                    let id = if keep_both(kind, their_id) {
+                        uuid::Uuid::new_v4().to_string()
+                    } else {
+                        m.my_id.clone()
+                    };
+                    (their_id.clone(), id)
+                })
+                .collect()
+        };
+        IdMaps {
+            service_types: map(RecordKind::ServiceType),
+            services: map(RecordKind::Service),
+            accounts: map(RecordKind::Account),
+        }
+    }
+}
+
+/// Labels match regardless of case and surrounding whitespace.
+fn label_key<T: Record>(record: &T) -> String {
+    record.label().trim().to_lowercase()
+}
+
+/// Matches records by id, then the rest by label where exactly one record
+/// of mine that isn't matched yet has it. Records of theirs that are in my
+/// trash are left out.
+fn match_records<T: Record>(
+    mine: &[T],
+    theirs: &[T],
+    trashed: &HashSet<(RecordKind, String)>,
+    my_key: impl Fn(&T) -> String,
+    their_key: impl Fn(&T) -> String,
+) -> HashMap<String, Match> {
+    let my_ids: HashSet<&str> = mine.iter().map(Record::id).collect();
+    let theirs: Vec<&T> = theirs
+        .iter()
+        .filter(|record| !trashed.contains(&(T::KIND, record.id().to_string())))
+        .collect();
+    let mut matches: HashMap<String, Match> = theirs
+        .iter()
+        .filter(|record| my_ids.contains(record.id()))
+        .map(|record| {
+            let m = Match {
+                my_id: record.id().to_string(),
+                by_label: false,
+            };
+            (record.id().to_string(), m)
+        })
+        .collect();
+
+    let mut taken: HashSet<String> = matches.keys().cloned().collect();
+    for record in theirs.iter().filter(|record| !my_ids.contains(record.id())) {
+        let key = their_key(record);
+        let mut candidates = mine
+            .iter()
+            .filter(|candidate| !taken.contains(candidate.id()) && my_key(candidate) == key);
+        if let (Some(candidate), None) = (candidates.next(), candidates.next()) {
+            taken.insert(candidate.id().to_string());
+            matches.insert(
+                record.id().to_string(),
+                Match {
+                    my_id: candidate.id().to_string(),
+                    by_label: true,
+                },
+            );
+        }
+    }
+    matches
+}
+
+fn compare_records<T: Record>(
+    preview: &mut MergePreview,
+    mine: &[T],
+    theirs: &[T],
+    remapped: &[T],
+    matches: &Matches,
+) {
+    let trashed = |id: &str| matches.is_trashed(T::KIND, id);
+    let matches = matches.of_kind(T::KIND);
+    for (record, remapped) in theirs.iter().zip(remapped) {
+        let m = matches.get(record.id());
+        let status = match m {
+            None if trashed(record.id()) => MatchStatus::InTrash,
+            None => MatchStatus::New,
+            Some(m) if mine.iter().any(|mine| mine.id() == m.my_id && mine.same_content(remapped)) => {
+                MatchStatus::Identical
+            }
+            Some(_) => MatchStatus::Conflict,
+        };
+        match status {
+            MatchStatus::New => preview.new += 1,
+            MatchStatus::Identical => preview.identical += 1,
+            MatchStatus::Conflict => preview.conflicts += 1,
+            MatchStatus::InTrash => preview.in_trash += 1,
+        }
+        let my_id = match status {
+            MatchStatus::InTrash => Some(record.id().to_string()),
+            _ => m.map(|m| m.my_id.clone()),
+        };
+        preview.items.push(MergeItem {
+            kind: T::KIND,
+            their_id: record.id().to_string(),
+            my_id,
+            label: record.label().to_string(),
+            matched_by_label: m.is_some_and(|m| m.by_label),
+            status,
+        });
+    }
+}
+
+// --- Applying ---
+
+struct IdMaps {
+    service_types: IdMap,
+    services: IdMap,
+    accounts: IdMap,
+}
+
+/// Their record ids from before remapping, in record order.
+struct TheirIds {
+    service_types: Vec<String>,
+    services: Vec<String>,
+    accounts: Vec<String>,
+}
+
+impl TheirIds {
+    fn of(vault: &Vault) -> Self {
+        TheirIds {
+            service_types: vault.service_types.iter().map(|st| st.id.clone()).collect(),
+            services: vault.services.iter().map(|s| s.id.clone()).collect(),
+            accounts: vault.accounts.iter().map(|a| a.id.clone()).collect(),
+        }
+    }
+}
+
+fn mapped(map: &IdMap, id: &str) -> String {
+    map.get(id).cloned().unwrap_or_else(|| id.to_string())
+}
+
+/// Rewrites the ids of `vault`'s records and every reference to them.
+fn remap_ids(vault: &mut Vault, maps: &IdMaps) {
+    // Linked-service fields hold a service id; find them before the types change.
+    let linked_fields: HashMap<String, Vec<String>> = vault
+        .service_types
+        .iter()
+        .map(|st| {
+            let keys = st
+                .fields
+                .iter()
+                .filter(|field| field.field_type == "linked_service")
+                .map(|field| field.key.clone())
+                .collect();
+            (st.id.clone(), keys)
+        })
+        .collect();
+
+    for service_type in &mut vault.service_types {
+        service_type.id = mapped(&maps.service_types, &service_type.id);
+        for field in &mut service_type.fields {
+            if let Some(linked) = &field.linked_service_type_id {
+                field.linked_service_type_id = Some(mapped(&maps.service_types, linked));
+            }
+        }
+    }
+
+    for service in &mut vault.services {
+        for key in linked_fields.get(&service.service_type_id).into_iter().flatten() {
+            if let Some(value) = service.data.get_mut(key) {
+                *value = mapped(&maps.services, value);
+            }
+        }
+        service.service_type_id = mapped(&maps.service_types, &service.service_type_id);
+        service.id = mapped(&maps.services, &service.id);
+    }
+
+    for account in &mut vault.accounts {
+        account.id = mapped(&maps.accounts, &account.id);
+        for service_id in &mut account.linked_services {
+            *service_id = mapped(&maps.services, service_id);
+        }
+    }
+}
+
+/// Applies their (remapped) records of one kind and returns how many were added.
+fn merge_records<T: Record>(
+    mine: &mut Vec<T>,
+    theirs: Vec<T>,
+    their_ids: &[String],
+    matches: &Matches,
+    resolve: &impl Fn(RecordKind, &str) -> Resolution,
+    counts: &mut ImportCounts,
+) -> usize {
+    let trashed = |id: &str| matches.is_trashed(T::KIND, id);
+    let matches = matches.of_kind(T::KIND);
+    let mut added = 0;
+    for (record, their_id) in theirs.into_iter().zip(their_ids) {
+        // Restoring my trashed copy would otherwise fail on the added one.
+        if trashed(their_id) {
+            counts.skipped += 1;
+            continue;
+        }
+        let Some(m) = matches.get(their_id) else {
+            mine.push(record);
+            added += 1;
+            continue;
+        };
+        match resolve(T::KIND, their_id) {
+            Resolution::KeepMine => counts.skipped += 1,
+            Resolution::KeepTheirs => {
+                if let Some(existing) = mine.iter_mut().find(|existing| existing.id() == m.my_id) {
+                    *existing = record;
+                    counts.replaced += 1;
+                }
+            }
+            Resolution::KeepBoth => {
+                mine.push(record);
+                added += 1;
+            }
+        }
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BackupSettings, Settings, Timestamps};
+    use crate::operations::Operation;
+
+    fn service_type(id: &str, name: &str) -> ServiceType {
+        ServiceType {
+            id: id.to_string(),
+            name: name.to_string(),
+            icon: "key".to_string(),
+            fields: Vec::new(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn service(id: &str, service_type_id: &str, label: &str) -> Service {
+        Service {
+            id: id.to_string(),
+            service_type_id: service_type_id.to_string(),
+            label: label.to_string(),
+            data: HashMap::from([("login".to_string(), label.to_string())]),
+            tags: Vec::new(),
+            field_history: HashMap::new(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn account(id: &str, label: &str, linked_services: &[&str]) -> Account {
+        Account {
+            id: id.to_string(),
+            label: label.to_string(),
+            notes: String::new(),
+            tags: Vec::new(),
+            linked_services: linked_services.iter().map(|id| id.to_string()).collect(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn vault(service_types: Vec<ServiceType>, services: Vec<Service>, accounts: Vec<Account>) -> Vault {
+        Vault {
+            version: crate::migrations::CURRENT_VAULT_VERSION.to_string(),
+            service_types,
+            services,
+            accounts,
+            settings: Settings {
+                auto_lock_minutes: 5,
+                backup: BackupSettings::default(),
+                trash_retention_days: 30,
+            },
+            trash: Vec::new(),
+        }
+    }
+
+    fn mine() -> Vault {
+        vault(
+            vec![service_type("email", "Email")],
+            vec![service("s1", "email", "Work")],
+            vec![account("a1", "Alice", &["s1"])],
+        )
+    }
+
+    fn item<'a>(preview: &'a MergePreview, their_id: &str) -> &'a MergeItem {
+        preview.items.iter().find(|item| item.their_id == their_id).unwrap()
+    }
+
+    #[test]
+    fn matches_by_id_then_by_label() {
+        let mut their_service = service("t1", "their-email", " work ");
+        their_service.data.insert("login".to_string(), "other".to_string());
+        let theirs = vault(
+            vec![service_type("their-email", " EMAIL ")],
+            vec![their_service, service("t2", "their-email", "Home")],
+            vec![account("a1", "Alicia", &[]), account("b1", "Bob", &[])],
+        );
+
+        let preview = preview_merge(&mine(), &theirs);
+        let service_type = item(&preview, "their-email");
+        assert_eq!(service_type.my_id.as_deref(), Some("email"));
+        assert!(service_type.matched_by_label);
+        // Labels match loosely, but the name is still part of the contents.
+        assert_eq!(service_type.status, MatchStatus::Conflict);
+
+        // Services match by label within the matched service type.
+        let service = item(&preview, "t1");
+        assert_eq!(service.my_id.as_deref(), Some("s1"));
+        assert_eq!(service.status, MatchStatus::Conflict);
+        assert_eq!(item(&preview, "t2").status, MatchStatus::New);
+
+        let account = item(&preview, "a1");
+        assert!(!account.matched_by_label);
+        assert_eq!(account.status, MatchStatus::Conflict);
+        assert_eq!(item(&preview, "b1").status, MatchStatus::New);
+        assert_eq!((preview.new, preview.identical, preview.conflicts), (2, 0, 3));
+    }
+
+    #[test]
+    fn remaps_references_to_the_ids_records_end_up_with() {
+        let theirs = vault(
+            vec![service_type("their-email", "Email")],
+            vec![service("t1", "their-email", "Work"), service("t2", "their-email", "Home")],
+            vec![account("b1", "Bob", &["t1", "t2"])],
+        );
+        let mut mine = mine();
+        mine.services[0].data.insert("login".to_string(), "mine".to_string());
+        let decisions = [MergeDecision {
+            kind: RecordKind::Service,
+            their_id: "t1".to_string(),
+            resolution: Resolution::KeepBoth,
+        }];
+
+        let counts = merge_vaults(&mut mine, theirs, &decisions);
+        assert_eq!((counts.services_added, counts.accounts_added, counts.skipped), (2, 1, 1));
+        assert_eq!(mine.service_types.len(), 1);
+        // Their services now belong to my service type; the one kept next to
+        // mine got a new id, which Bob's account links to.
+        assert!(mine.services.iter().all(|s| s.service_type_id == "email"));
+        let kept_both = mine.services.iter().find(|s| s.label == "Work" && s.id != "s1").unwrap();
+        let home = mine.services.iter().find(|s| s.label == "Home").unwrap();
+        assert_eq!(home.id, "t2");
+        let bob = mine.accounts.iter().find(|a| a.id == "b1").unwrap();
+        assert_eq!(bob.linked_services, [kept_both.id.clone(), "t2".to_string()]);
+    }
+
+    #[test]
+    fn leaves_records_in_my_trash_alone() {
+        let mut mine = mine();
+        Operation::TrashAccount {
+            account_id: "a1".to_string(),
+            deleted_at: "2024-01-01T00:00:00+00:00".to_string(),
+        }
+        .apply(&mut mine)
+        .unwrap();
+        let theirs = vault(Vec::new(), Vec::new(), vec![account("a1", "Alice", &[])]);
+
+        let preview = preview_merge(&mine, &theirs);
+        assert_eq!(item(&preview, "a1").status, MatchStatus::InTrash);
+        assert_eq!((preview.new, preview.in_trash), (0, 1));
+
+        let counts = merge_vaults(&mut mine, theirs, &[]);
+        assert_eq!((counts.accounts_added, counts.skipped), (0, 1));
+        assert!(mine.accounts.is_empty());
+        Operation::RestoreFromTrash {
+            item: TrashKey {
+                kind: RecordKind::Account,
+                id: "a1".to_string(),
+            },
+        }
+        .apply(&mut mine)
+        .unwrap();
+        assert_eq!(mine.accounts.len(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+//! `migrations.rs`: Upgrades vault JSON written by older versions step by step.
+
+use crate::models::{default_trash_retention_days, BackupSettings};
+use chrono::Utc;
+use log::info;
+use serde_json::{Map, Value};
+
+/// Schema version of the vault JSON written by this build. Adding a migration
+/// means bumping this to its `to` version.
+pub const CURRENT_VAULT_VERSION: &str = "0.5.0";
+
+/// Vaults written before the version was checked all carry this one.
+const OLDEST_VAULT_VERSION: &str = "0.2.0";
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("Invalid vault version: {0}")]
+    InvalidVersion(String),
+    #[error("The vault was written by a newer version of AccMan (schema {0}); update the app to open it")]
+    NewerVersion(String),
+    #[error("Failed to migrate the vault to schema {to}: {reason}")]
+    Failed { to: &'static str, reason: String },
+}
+
+/// One step of the upgrade path. It is applied to vaults older than `to` and
+/// only has to handle JSON in the shape of the version before it.
+struct Migration {
+    to: &'static str,
+    migrate: fn(&mut Map<String, Value>) -> Result<(), String>,
+}
+
+/// Ordered oldest first; the last entry's `to` is `CURRENT_VAULT_VERSION`.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: "0.3.0",
+        migrate: add_backup_settings,
+    },
+    Migration {
+        to: "0.4.0",
+        migrate: add_trash,
+    },
+    Migration {
+        to: "0.5.0",
+        migrate: add_timestamps,
+    },
+];
+
+type Version = (u32, u32, u32);
+
+/// Applies every migration newer than the vault's version, in order. Fails
+/// for vaults written by a newer version. Returns whether anything changed.
+pub fn migrate(vault: &mut Value) -> Result<bool, MigrationError> {
+    let version = vault_version(vault)?;
+    check_not_newer(version)?;
+
+    let mut migrated = false;
+    for migration in MIGRATIONS {
+        if version >= parse_version(migration.to)? {
+            continue;
+        }
+        let object = vault.as_object_mut().ok_or_else(|| MigrationError::Failed {
+            to: migration.to,
+            reason: "the vault is not a JSON object".to_string(),
+        })?;
+        (migration.migrate)(object).map_err(|reason| MigrationError::Failed {
+            to: migration.to,
+            reason,
+        })?;
+        object.insert("version".to_string(), Value::from(migration.to));
+        info!("Vault migrated to schema {}", migration.to);
+        migrated = true;
+    }
+    Ok(migrated)
+}
+
+// --- Migrations ---
+
+/// 0.3.0: backup settings are stored explicitly instead of being defaulted on load.
+fn add_backup_settings(vault: &mut Map<String, Value>) -> Result<(), String> {
+    let settings = vault
+        .get_mut("settings")
+        .and_then(Value::as_object_mut)
+        .ok_or("missing settings")?;
+    if !settings.contains_key("backup") {
+        let backup = serde_json::to_value(BackupSettings::default()).map_err(|e| e.to_string())?;
+        settings.insert("backup".to_string(), backup);
+    }
+    Ok(())
+}
+
+/// 0.4.0: deleted records go to a trash, purged after a retention period.
+fn add_trash(vault: &mut Map<String, Value>) -> Result<(), String> {
+    let settings = vault
+        .get_mut("settings")
+        .and_then(Value::as_object_mut)
+        .ok_or("missing settings")?;
+    if !settings.contains_key("trashRetentionDays") {
+        settings.insert(
+            "trashRetentionDays".to_string(),
+            Value::from(default_trash_retention_days()),
+        );
+    }
+    if !vault.contains_key("trash") {
+        vault.insert("trash".to_string(), Value::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+/// 0.5.0: records carry when they were created and last changed. Existing
+/// ones get the time of the migration, as the real times are unknown.
+fn add_timestamps(vault: &mut Map<String, Value>) -> Result<(), String> {
+    let now = Value::from(Utc::now().to_rfc3339());
+    for list in ["serviceTypes", "services", "accounts"] {
+        let records = vault
+            .get_mut(list)
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| format!("missing {}", list))?;
+        for record in records {
+            add_record_timestamps(record, &now)?;
+        }
+    }
+    // Records in the trash sit under their kind, e.g. `{"kind": "service", "service": {..}}`.
+    if let Some(trash) = vault.get_mut("trash").and_then(Value::as_array_mut) {
+        for item in trash.iter_mut().filter_map(Value::as_object_mut) {
+            let kind = item.get("kind").and_then(Value::as_str).map(str::to_string);
+            if let Some(record) = kind.and_then(|kind| item.get_mut(&kind)) {
+                add_record_timestamps(record, &now)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn add_record_timestamps(record: &mut Value, now: &Value) -> Result<(), String> {
+    let record = record.as_object_mut().ok_or("a record is not a JSON object")?;
+    for key in ["createdAt", "updatedAt"] {
+        if !record.contains_key(key) {
+            record.insert(key.to_string(), now.clone());
+        }
+    }
+    Ok(())
+}
+
+// --- Helpers ---
+
+fn vault_version(vault: &Value) -> Result<Version, MigrationError> {
+    match vault.get("version") {
+        Some(Value::String(version)) => parse_version(version),
+        None => parse_version(OLDEST_VAULT_VERSION),
+        Some(other) => Err(MigrationError::InvalidVersion(other.to_string())),
+    }
+}
+
+fn check_not_newer(version: Version) -> Result<(), MigrationError> {
+    if version > parse_version(CURRENT_VAULT_VERSION)? {
+        let (major, minor, patch) = version;
+        return Err(MigrationError::NewerVersion(format!("{}.{}.{}", major, minor, patch)));
+    }
+    Ok(())
+}
+
+fn parse_version(version: &str) -> Result<Version, MigrationError> {
+    let invalid = || MigrationError::InvalidVersion(version.to_string());
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().map_err(|_| invalid()));
+    let parsed = (
+        parts.next().ok_or_else(invalid)??,
+        parts.next().ok_or_else(invalid)??,
+        parts.next().ok_or_else(invalid)??,
+    );
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Vault;
+    use serde_json::json;
+
+    /// A vault as 0.2.0 wrote it: no backup settings, trash or timestamps.
+    fn vault_0_2_0() -> Value {
+        json!({
+            "version": "0.2.0",
+            "serviceTypes": [{"id": "email", "name": "Email", "icon": "mail", "fields": []}],
+            "services": [{"id": "s1", "serviceTypeId": "email", "label": "Work", "data": {"login": "me"}, "tags": []}],
+            "accounts": [{"id": "a1", "label": "Me", "notes": "", "tags": [], "linkedServices": ["s1"]}],
+            "settings": {"autoLockMinutes": 5}
+        })
+    }
+
+    #[test]
+    fn migrates_0_2_0_to_current() {
+        let mut value = vault_0_2_0();
+        assert!(migrate(&mut value).unwrap());
+        assert_eq!(value["version"], CURRENT_VAULT_VERSION);
+        assert!(value["settings"]["backup"].is_object());
+        assert_eq!(value["settings"]["trashRetentionDays"], default_trash_retention_days());
+        assert_eq!(value["trash"], json!([]));
+        for list in ["serviceTypes", "services", "accounts"] {
+            assert!(value[list][0]["createdAt"].is_string(), "{} has no createdAt", list);
+            assert!(value[list][0]["updatedAt"].is_string(), "{} has no updatedAt", list);
+        }
+
+        let vault: Vault = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(vault.accounts[0].linked_services, ["s1"]);
+        assert_eq!(vault.services[0].data["login"], "me");
+
+        // Migrating again changes nothing.
+        assert!(!migrate(&mut value).unwrap());
+    }
+
+    #[test]
+    fn treats_unversioned_vaults_as_the_oldest() {
+        let mut value = vault_0_2_0();
+        value.as_object_mut().unwrap().remove("version");
+        assert!(migrate(&mut value).unwrap());
+        assert_eq!(value["version"], CURRENT_VAULT_VERSION);
+    }
+
+    #[test]
+    fn refuses_newer_vaults() {
+        let mut value = vault_0_2_0();
+        value["version"] = json!("99.0.0");
+        assert!(matches!(migrate(&mut value), Err(MigrationError::NewerVersion(_))));
+        assert_eq!(value["version"], "99.0.0");
+    }
+}
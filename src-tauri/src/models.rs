@@ -5,9 +5,41 @@ use serde::{Deserialize, Serialize};
 #[serde(rename_all = "camelCase")]
 pub struct Settings {
     pub auto_lock_minutes: u32,
+    #[serde(default)]
+    pub backup: BackupSettings,
+    /// Deleted records are purged from the trash after this many days. 0 keeps
+    /// them until the trash is emptied.
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
 }
 
+pub fn default_trash_retention_days() -> u32 {
+    30
+}
+
+/// Retention policy for the automatic backups, taken before each full save and
+/// before the first change of each session.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BackupSettings {
+    pub enabled: bool,
+    /// The most recent backups are always kept.
+    pub keep_last: u32,
+    /// Beyond those, the newest backup of each of the last this many days is kept.
+    pub keep_daily_days: u32,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        BackupSettings {
+            enabled: true,
+            keep_last: 20,
+            keep_daily_days: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ServiceField {
     pub id: String,
@@ -21,16 +53,87 @@ pub struct ServiceField {
     pub linked_service_type_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ServiceType {
     pub id: String,
     pub name: String,
     pub icon: String,
     pub fields: Vec<ServiceField>,
+    #[serde(flatten)]
+    pub timestamps: Timestamps,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// When a record was created, last changed and last used. Maintained by the
+/// backend; whatever the frontend sends here is ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Timestamps {
+    /// RFC 3339 timestamp, like the others.
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default)]
+    pub updated_at: String,
+    /// Set when a secret of the record is copied or revealed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<String>,
+}
+
+impl Timestamps {
+    pub fn new(now: &str) -> Timestamps {
+        Timestamps {
+            created_at: now.to_string(),
+            updated_at: now.to_string(),
+            last_used_at: None,
+        }
+    }
+
+    /// The timestamps of a new version of the record these belong to.
+    pub fn updated(&self, now: &str) -> Timestamps {
+        Timestamps {
+            updated_at: now.to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Records that carry `Timestamps`.
+pub trait Timestamped {
+    fn timestamps(&self) -> &Timestamps;
+    fn timestamps_mut(&mut self) -> &mut Timestamps;
+}
+
+impl Timestamped for ServiceType {
+    fn timestamps(&self) -> &Timestamps {
+        &self.timestamps
+    }
+    fn timestamps_mut(&mut self) -> &mut Timestamps {
+        &mut self.timestamps
+    }
+}
+
+impl Timestamped for Service {
+    fn timestamps(&self) -> &Timestamps {
+        &self.timestamps
+    }
+    fn timestamps_mut(&mut self) -> &mut Timestamps {
+        &mut self.timestamps
+    }
+}
+
+impl Timestamped for Account {
+    fn timestamps(&self) -> &Timestamps {
+        &self.timestamps
+    }
+    fn timestamps_mut(&mut self) -> &mut Timestamps {
+        &mut self.timestamps
+    }
+}
+
+/// How many earlier values are kept per field of a service.
+pub const MAX_FIELD_HISTORY: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Service {
     pub id: String,
@@ -38,9 +141,46 @@ pub struct Service {
     pub label: String,
     pub data: std::collections::HashMap<String, String>,
     pub tags: Vec<String>,
+    /// Earlier values of each field in `data`, oldest first. Maintained by the
+    /// backend; whatever the frontend sends here is ignored.
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub field_history: std::collections::HashMap<String, Vec<FieldValue>>,
+    #[serde(flatten)]
+    pub timestamps: Timestamps,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A value a service field had before it was changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldValue {
+    pub value: String,
+    /// RFC 3339 timestamp of when the value was replaced.
+    pub replaced_at: String,
+}
+
+impl Service {
+    /// Takes the field history of `previous`, the stored version of this
+    /// service, and adds the values this version replaces to it.
+    pub fn with_history_from(mut self, previous: &Service, replaced_at: &str) -> Service {
+        self.field_history = previous.field_history.clone();
+        for (key, old_value) in &previous.data {
+            if old_value.is_empty() || self.data.get(key) == Some(old_value) {
+                continue;
+            }
+            let history = self.field_history.entry(key.clone()).or_default();
+            history.push(FieldValue {
+                value: old_value.clone(),
+                replaced_at: replaced_at.to_string(),
+            });
+            if history.len() > MAX_FIELD_HISTORY {
+                history.remove(0);
+            }
+        }
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Account {
     pub id: String,
@@ -48,6 +188,8 @@ pub struct Account {
     pub notes: String,
     pub tags: Vec<String>,
     pub linked_services: Vec<String>,
+    #[serde(flatten)]
+    pub timestamps: Timestamps,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -58,4 +200,42 @@ pub struct Vault {
     pub services: Vec<Service>,
     pub accounts: Vec<Account>,
     pub settings: Settings,
+    #[serde(default)]
+    pub trash: Vec<TrashedItem>,
+}
+
+/// A deleted record, kept in the trash until it is restored or purged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedItem {
+    #[serde(flatten)]
+    pub record: TrashedRecord,
+    /// RFC 3339 timestamp.
+    pub deleted_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum TrashedRecord {
+    ServiceType {
+        service_type: ServiceType,
+    },
+    Service {
+        service: Service,
+        /// Accounts the service was linked to, linked again on restore.
+        linked_account_ids: Vec<String>,
+    },
+    Account {
+        account: Account,
+    },
+}
+
+impl TrashedItem {
+    pub fn id(&self) -> &str {
+        match &self.record {
+            TrashedRecord::ServiceType { service_type } => &service_type.id,
+            TrashedRecord::Service { service, .. } => &service.id,
+            TrashedRecord::Account { account } => &account.id,
+        }
+    }
 }
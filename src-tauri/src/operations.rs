@@ -0,0 +1,901 @@
+//! `operations.rs`: The changes commands make to a vault, in a form that can be
+//! recorded in the journal and applied again when the vault is loaded.
+
+use crate::merge::RecordKind;
+use crate::models::{
+    Account, Service, ServiceType, Settings, Timestamped, TrashedItem, TrashedRecord, Vault,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OperationError {
+    #[error("A Service Type with ID '{0}' already exists.")]
+    ServiceTypeExists(String),
+    #[error("Service Type with ID '{0}' not found.")]
+    ServiceTypeNotFound(String),
+    #[error("Service with ID '{0}' not found.")]
+    ServiceNotFound(String),
+    #[error("Account with ID '{0}' not found.")]
+    AccountNotFound(String),
+    #[error("Item with ID '{0}' is not in the trash.")]
+    NotInTrash(String),
+    #[error("A record with ID '{0}' already exists.")]
+    RecordExists(String),
+}
+
+/// Identifies a record in the trash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashKey {
+    pub kind: RecordKind,
+    pub id: String,
+}
+
+impl TrashKey {
+    pub fn of(item: &TrashedItem) -> TrashKey {
+        let kind = match item.record {
+            TrashedRecord::ServiceType { .. } => RecordKind::ServiceType,
+            TrashedRecord::Service { .. } => RecordKind::Service,
+            TrashedRecord::Account { .. } => RecordKind::Account,
+        };
+        TrashKey {
+            kind,
+            id: item.id().to_string(),
+        }
+    }
+}
+
+/// One change to the vault. Anything random, such as new ids, is decided
+/// before the operation is built, so applying the same operations to the
+/// same vault always has the same result. An operation that fails leaves
+/// the vault unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum Operation {
+    UpdateSettings {
+        settings: Settings,
+    },
+    AddServiceType {
+        service_type: ServiceType,
+    },
+    UpdateServiceType {
+        service_type: ServiceType,
+    },
+    /// Deletes a service type for good.
+    DeleteServiceType {
+        service_type_id: String,
+    },
+    /// Moves a service type to the trash.
+    TrashServiceType {
+        service_type_id: String,
+        deleted_at: String,
+    },
+    /// Adds a service, linking it to `account_id` if given.
+    AddService {
+        service: Service,
+        account_id: Option<String>,
+    },
+    AddServices {
+        services: Vec<Service>,
+    },
+    UpdateService {
+        service: Service,
+    },
+    DeleteService {
+        service_id: String,
+    },
+    /// Deletes services for good and unlinks them from every account.
+    DeleteServices {
+        service_ids: Vec<String>,
+    },
+    /// Moves services to the trash and unlinks them from every account,
+    /// remembering the links. Ids not in the vault are skipped.
+    TrashServices {
+        service_ids: Vec<String>,
+        deleted_at: String,
+    },
+    AddAccount {
+        account: Account,
+    },
+    /// Adds accounts created in bulk, already linked to their services.
+    AddAccounts {
+        accounts: Vec<Account>,
+    },
+    UpdateAccount {
+        account: Account,
+    },
+    /// Deletes an account for good.
+    DeleteAccount {
+        account_id: String,
+    },
+    /// Moves an account to the trash.
+    TrashAccount {
+        account_id: String,
+        deleted_at: String,
+    },
+    LinkServices {
+        account_id: String,
+        service_ids: Vec<String>,
+    },
+    /// Sets when a record was last used.
+    SetLastUsed {
+        kind: RecordKind,
+        id: String,
+        last_used_at: Option<String>,
+    },
+    /// Puts a record back from the trash, the most recently deleted copy if
+    /// there are several. A service is linked again to those of its accounts
+    /// that still exist.
+    RestoreFromTrash {
+        item: TrashKey,
+    },
+    /// Removes records from the trash for good. Items not in the trash are skipped.
+    PurgeTrash {
+        items: Vec<TrashKey>,
+    },
+    /// Puts purged items back into the trash, to undo `PurgeTrash`.
+    ReturnToTrash {
+        items: Vec<TrashedItem>,
+    },
+    /// Several operations applied as one change. If any of them fails, none
+    /// is applied.
+    Batch {
+        operations: Vec<Operation>,
+    },
+}
+
+impl Operation {
+    pub fn apply(&self, vault: &mut Vault) -> Result<(), OperationError> {
+        match self {
+            Operation::UpdateSettings { settings } => {
+                vault.settings = settings.clone();
+            }
+            Operation::AddServiceType { service_type } => {
+                if vault.service_types.iter().any(|st| st.id == service_type.id) {
+                    return Err(OperationError::ServiceTypeExists(service_type.id.clone()));
+                }
+                vault.service_types.push(service_type.clone());
+            }
+            Operation::UpdateServiceType { service_type } => {
+                let existing = vault
+                    .service_types
+                    .iter_mut()
+                    .find(|st| st.id == service_type.id)
+                    .ok_or_else(|| OperationError::ServiceTypeNotFound(service_type.id.clone()))?;
+                *existing = service_type.clone();
+            }
+            Operation::DeleteServiceType { service_type_id } => {
+                let initial_len = vault.service_types.len();
+                vault.service_types.retain(|st| &st.id != service_type_id);
+                if vault.service_types.len() == initial_len {
+                    return Err(OperationError::ServiceTypeNotFound(service_type_id.clone()));
+                }
+            }
+            Operation::TrashServiceType {
+                service_type_id,
+                deleted_at,
+            } => {
+                let index = vault
+                    .service_types
+                    .iter()
+                    .position(|st| &st.id == service_type_id)
+                    .ok_or_else(|| OperationError::ServiceTypeNotFound(service_type_id.clone()))?;
+                let service_type = vault.service_types.remove(index);
+                vault.trash.push(TrashedItem {
+                    record: TrashedRecord::ServiceType { service_type },
+                    deleted_at: deleted_at.clone(),
+                });
+            }
+            Operation::AddService { service, account_id } => {
+                check_new_ids(vault.services.iter().map(|s| s.id.as_str()), [service.id.as_str()])?;
+                if let Some(account_id) = account_id {
+                    let account = vault
+                        .accounts
+                        .iter_mut()
+                        .find(|a| &a.id == account_id)
+                        .ok_or_else(|| OperationError::AccountNotFound(account_id.clone()))?;
+                    account.linked_services.push(service.id.clone());
+                    account.linked_services.sort();
+                    account.linked_services.dedup();
+                }
+                vault.services.push(service.clone());
+            }
+            Operation::AddServices { services } => {
+                check_new_ids(
+                    vault.services.iter().map(|s| s.id.as_str()),
+                    services.iter().map(|s| s.id.as_str()),
+                )?;
+                vault.services.extend(services.iter().cloned());
+            }
+            Operation::UpdateService { service } => {
+                let existing = vault
+                    .services
+                    .iter_mut()
+                    .find(|s| s.id == service.id)
+                    .ok_or_else(|| OperationError::ServiceNotFound(service.id.clone()))?;
+                *existing = service.clone();
+            }
+            Operation::DeleteService { service_id } => {
+                let initial_len = vault.services.len();
+                vault.services.retain(|s| &s.id != service_id);
+                if vault.services.len() == initial_len {
+                    return Err(OperationError::ServiceNotFound(service_id.clone()));
+                }
+            }
+            Operation::DeleteServices { service_ids } => {
+                vault.services.retain(|s| !service_ids.contains(&s.id));
+                for account in &mut vault.accounts {
+                    account.linked_services.retain(|id| !service_ids.contains(id));
+                }
+            }
+            Operation::TrashServices {
+                service_ids,
+                deleted_at,
+            } => {
+                let (trashed, kept) = std::mem::take(&mut vault.services)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|s| service_ids.contains(&s.id));
+                vault.services = kept;
+                for service in trashed {
+                    let mut linked_account_ids = Vec::new();
+                    for account in &mut vault.accounts {
+                        if account.linked_services.contains(&service.id) {
+                            account.linked_services.retain(|id| id != &service.id);
+                            linked_account_ids.push(account.id.clone());
+                        }
+                    }
+                    vault.trash.push(TrashedItem {
+                        record: TrashedRecord::Service {
+                            service,
+                            linked_account_ids,
+                        },
+                        deleted_at: deleted_at.clone(),
+                    });
+                }
+            }
+            Operation::AddAccount { account } => {
+                check_new_ids(vault.accounts.iter().map(|a| a.id.as_str()), [account.id.as_str()])?;
+                vault.accounts.push(account.clone());
+            }
+            Operation::AddAccounts { accounts } => {
+                check_new_ids(
+                    vault.accounts.iter().map(|a| a.id.as_str()),
+                    accounts.iter().map(|a| a.id.as_str()),
+                )?;
+                vault.accounts.extend(accounts.iter().cloned());
+            }
+            Operation::UpdateAccount { account } => {
+                let existing = vault
+                    .accounts
+                    .iter_mut()
+                    .find(|a| a.id == account.id)
+                    .ok_or_else(|| OperationError::AccountNotFound(account.id.clone()))?;
+                *existing = account.clone();
+            }
+            Operation::DeleteAccount { account_id } => {
+                let initial_len = vault.accounts.len();
+                vault.accounts.retain(|a| &a.id != account_id);
+                if vault.accounts.len() == initial_len {
+                    return Err(OperationError::AccountNotFound(account_id.clone()));
+                }
+            }
+            Operation::TrashAccount {
+                account_id,
+                deleted_at,
+            } => {
+                let index = vault
+                    .accounts
+                    .iter()
+                    .position(|a| &a.id == account_id)
+                    .ok_or_else(|| OperationError::AccountNotFound(account_id.clone()))?;
+                let account = vault.accounts.remove(index);
+                vault.trash.push(TrashedItem {
+                    record: TrashedRecord::Account { account },
+                    deleted_at: deleted_at.clone(),
+                });
+            }
+            Operation::LinkServices {
+                account_id,
+                service_ids,
+            } => {
+                let account = vault
+                    .accounts
+                    .iter_mut()
+                    .find(|a| &a.id == account_id)
+                    .ok_or_else(|| OperationError::AccountNotFound(account_id.clone()))?;
+                account.linked_services.extend(service_ids.iter().cloned());
+                account.linked_services.sort();
+                account.linked_services.dedup();
+            }
+            Operation::SetLastUsed {
+                kind,
+                id,
+                last_used_at,
+            } => {
+                let record = timestamped_mut(vault, *kind, id)?;
+                record.timestamps_mut().last_used_at = last_used_at.clone();
+            }
+            Operation::RestoreFromTrash { item } => restore_from_trash(vault, item)?,
+            Operation::PurgeTrash { items } => {
+                vault.trash.retain(|trashed| !items.contains(&TrashKey::of(trashed)));
+            }
+            Operation::ReturnToTrash { items } => {
+                vault.trash.extend(items.iter().cloned());
+            }
+            Operation::Batch { operations } => {
+                let mut staged = vault.clone();
+                for operation in operations {
+                    operation.apply(&mut staged)?;
+                }
+                *vault = staged;
+            }
+        }
+        Ok(())
+    }
+
+    /// This operation with every record it updates keeping the time it was
+    /// last used in `vault`. Marking a record used can't be undone, so undoing
+    /// or redoing an edit must not take back uses recorded since.
+    pub fn keeping_last_used(&self, vault: &Vault) -> Operation {
+        let mut operation = self.clone();
+        operation.keep_last_used(vault);
+        operation
+    }
+
+    fn keep_last_used(&mut self, vault: &Vault) {
+        match self {
+            Operation::UpdateServiceType { service_type } => {
+                if let Some(current) = vault.service_types.iter().find(|st| st.id == service_type.id) {
+                    service_type.timestamps.last_used_at = current.timestamps.last_used_at.clone();
+                }
+            }
+            Operation::UpdateService { service } => {
+                if let Some(current) = vault.services.iter().find(|s| s.id == service.id) {
+                    service.timestamps.last_used_at = current.timestamps.last_used_at.clone();
+                }
+            }
+            Operation::UpdateAccount { account } => {
+                if let Some(current) = vault.accounts.iter().find(|a| a.id == account.id) {
+                    account.timestamps.last_used_at = current.timestamps.last_used_at.clone();
+                }
+            }
+            Operation::Batch { operations } => {
+                for operation in operations {
+                    operation.keep_last_used(vault);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The operation that undoes this one. It has to be worked out from the
+    /// vault as it is before this operation is applied, and only undoes it if
+    /// applying it succeeded. Records brought back are added at the end of
+    /// their lists rather than where they were.
+    pub fn inverse(&self, vault: &Vault) -> Operation {
+        match self {
+            Operation::UpdateSettings { .. } => Operation::UpdateSettings {
+                settings: vault.settings.clone(),
+            },
+            Operation::AddServiceType { service_type } => Operation::DeleteServiceType {
+                service_type_id: service_type.id.clone(),
+            },
+            Operation::UpdateServiceType { service_type } => batch(
+                vault
+                    .service_types
+                    .iter()
+                    .filter(|st| st.id == service_type.id)
+                    .map(|st| Operation::UpdateServiceType {
+                        service_type: st.clone(),
+                    })
+                    .collect(),
+            ),
+            Operation::DeleteServiceType { service_type_id } => batch(
+                vault
+                    .service_types
+                    .iter()
+                    .filter(|st| &st.id == service_type_id)
+                    .map(|st| Operation::AddServiceType {
+                        service_type: st.clone(),
+                    })
+                    .collect(),
+            ),
+            Operation::TrashServiceType { service_type_id, .. } => Operation::RestoreFromTrash {
+                item: TrashKey {
+                    kind: RecordKind::ServiceType,
+                    id: service_type_id.clone(),
+                },
+            },
+            Operation::AddService { service, account_id } => {
+                let mut operations = vec![Operation::DeleteService {
+                    service_id: service.id.clone(),
+                }];
+                operations.extend(restore_accounts(vault, |a| Some(&a.id) == account_id.as_ref()));
+                batch(operations)
+            }
+            Operation::AddServices { services } => batch(
+                services
+                    .iter()
+                    .map(|s| Operation::DeleteService {
+                        service_id: s.id.clone(),
+                    })
+                    .collect(),
+            ),
+            Operation::UpdateService { service } => batch(
+                vault
+                    .services
+                    .iter()
+                    .filter(|s| s.id == service.id)
+                    .map(|s| Operation::UpdateService { service: s.clone() })
+                    .collect(),
+            ),
+            Operation::DeleteService { service_id } => Operation::AddServices {
+                services: vault.services.iter().filter(|s| &s.id == service_id).cloned().collect(),
+            },
+            Operation::DeleteServices { service_ids } => {
+                let mut operations = vec![Operation::AddServices {
+                    services: vault
+                        .services
+                        .iter()
+                        .filter(|s| service_ids.contains(&s.id))
+                        .cloned()
+                        .collect(),
+                }];
+                operations.extend(restore_accounts(vault, |a| {
+                    a.linked_services.iter().any(|id| service_ids.contains(id))
+                }));
+                batch(operations)
+            }
+            Operation::TrashServices { service_ids, .. } => {
+                let mut operations: Vec<Operation> = vault
+                    .services
+                    .iter()
+                    .filter(|s| service_ids.contains(&s.id))
+                    .map(|s| Operation::RestoreFromTrash {
+                        item: TrashKey {
+                            kind: RecordKind::Service,
+                            id: s.id.clone(),
+                        },
+                    })
+                    .collect();
+                operations.extend(restore_accounts(vault, |a| {
+                    a.linked_services.iter().any(|id| service_ids.contains(id))
+                }));
+                batch(operations)
+            }
+            Operation::AddAccount { account } => Operation::DeleteAccount {
+                account_id: account.id.clone(),
+            },
+            Operation::AddAccounts { accounts } => batch(
+                accounts
+                    .iter()
+                    .map(|a| Operation::DeleteAccount {
+                        account_id: a.id.clone(),
+                    })
+                    .collect(),
+            ),
+            Operation::UpdateAccount { account: Account { id, .. } }
+            | Operation::LinkServices { account_id: id, .. } => batch(restore_accounts(vault, |a| &a.id == id)),
+            Operation::DeleteAccount { account_id } => Operation::AddAccounts {
+                accounts: vault.accounts.iter().filter(|a| &a.id == account_id).cloned().collect(),
+            },
+            Operation::TrashAccount { account_id, .. } => Operation::RestoreFromTrash {
+                item: TrashKey {
+                    kind: RecordKind::Account,
+                    id: account_id.clone(),
+                },
+            },
+            Operation::SetLastUsed { kind, id, .. } => {
+                let last_used_at = timestamped(vault, *kind, id)
+                    .and_then(|record| record.timestamps().last_used_at.clone());
+                Operation::SetLastUsed {
+                    kind: *kind,
+                    id: id.clone(),
+                    last_used_at,
+                }
+            }
+            Operation::RestoreFromTrash { item } => {
+                let Some(trashed) = trashed_index(vault, item).map(|index| &vault.trash[index]) else {
+                    return batch(Vec::new());
+                };
+                let deleted_at = trashed.deleted_at.clone();
+                match &trashed.record {
+                    TrashedRecord::ServiceType { service_type } => Operation::TrashServiceType {
+                        service_type_id: service_type.id.clone(),
+                        deleted_at,
+                    },
+                    TrashedRecord::Service {
+                        service,
+                        linked_account_ids,
+                    } => {
+                        let mut operations = vec![Operation::TrashServices {
+                            service_ids: vec![service.id.clone()],
+                            deleted_at,
+                        }];
+                        operations.extend(restore_accounts(vault, |a| linked_account_ids.contains(&a.id)));
+                        batch(operations)
+                    }
+                    TrashedRecord::Account { account } => Operation::TrashAccount {
+                        account_id: account.id.clone(),
+                        deleted_at,
+                    },
+                }
+            }
+            Operation::PurgeTrash { items } => Operation::ReturnToTrash {
+                items: vault
+                    .trash
+                    .iter()
+                    .filter(|trashed| items.contains(&TrashKey::of(trashed)))
+                    .cloned()
+                    .collect(),
+            },
+            Operation::ReturnToTrash { items } => Operation::PurgeTrash {
+                items: items.iter().map(TrashKey::of).collect(),
+            },
+            Operation::Batch { operations } => {
+                let mut staged = vault.clone();
+                let mut inverses = Vec::new();
+                for operation in operations {
+                    inverses.push(operation.inverse(&staged));
+                    if operation.apply(&mut staged).is_err() {
+                        break;
+                    }
+                }
+                inverses.reverse();
+                Operation::Batch { operations: inverses }
+            }
+        }
+    }
+}
+
+/// A single operation as itself, anything else as a batch.
+fn batch(mut operations: Vec<Operation>) -> Operation {
+    if operations.len() == 1 {
+        operations.remove(0)
+    } else {
+        Operation::Batch { operations }
+    }
+}
+
+/// Operations that set the accounts matching `filter` back to how they are now.
+fn restore_accounts(vault: &Vault, filter: impl Fn(&Account) -> bool) -> Vec<Operation> {
+    vault
+        .accounts
+        .iter()
+        .filter(|a| filter(a))
+        .map(|a| Operation::UpdateAccount { account: a.clone() })
+        .collect()
+}
+
+fn timestamped<'a>(vault: &'a Vault, kind: RecordKind, id: &str) -> Option<&'a dyn Timestamped> {
+    match kind {
+        RecordKind::ServiceType => vault.service_types.iter().find(|st| st.id == id).map(|st| st as _),
+        RecordKind::Service => vault.services.iter().find(|s| s.id == id).map(|s| s as _),
+        RecordKind::Account => vault.accounts.iter().find(|a| a.id == id).map(|a| a as _),
+    }
+}
+
+fn timestamped_mut<'a>(
+    vault: &'a mut Vault,
+    kind: RecordKind,
+    id: &str,
+) -> Result<&'a mut dyn Timestamped, OperationError> {
+    let record: Option<&mut dyn Timestamped> = match kind {
+        RecordKind::ServiceType => vault.service_types.iter_mut().find(|st| st.id == id).map(|st| st as _),
+        RecordKind::Service => vault.services.iter_mut().find(|s| s.id == id).map(|s| s as _),
+        RecordKind::Account => vault.accounts.iter_mut().find(|a| a.id == id).map(|a| a as _),
+    };
+    record.ok_or_else(|| match kind {
+        RecordKind::ServiceType => OperationError::ServiceTypeNotFound(id.to_string()),
+        RecordKind::Service => OperationError::ServiceNotFound(id.to_string()),
+        RecordKind::Account => OperationError::AccountNotFound(id.to_string()),
+    })
+}
+
+/// Fails with `RecordExists` if one of `ids` is taken already, or given twice.
+fn check_new_ids<'a>(
+    existing: impl Iterator<Item = &'a str>,
+    ids: impl IntoIterator<Item = &'a str>,
+) -> Result<(), OperationError> {
+    let mut taken: HashSet<&str> = existing.collect();
+    for id in ids {
+        if !taken.insert(id) {
+            return Err(OperationError::RecordExists(id.to_string()));
+        }
+    }
+    Ok(())
+}
+
+fn trashed_index(vault: &Vault, key: &TrashKey) -> Option<usize> {
+    vault.trash.iter().rposition(|trashed| &TrashKey::of(trashed) == key)
+}
+
+fn restore_from_trash(vault: &mut Vault, key: &TrashKey) -> Result<(), OperationError> {
+    let index = trashed_index(vault, key).ok_or_else(|| OperationError::NotInTrash(key.id.clone()))?;
+    let exists = match key.kind {
+        RecordKind::ServiceType => vault.service_types.iter().any(|st| st.id == key.id),
+        RecordKind::Service => vault.services.iter().any(|s| s.id == key.id),
+        RecordKind::Account => vault.accounts.iter().any(|a| a.id == key.id),
+    };
+    if exists {
+        return Err(OperationError::RecordExists(key.id.clone()));
+    }
+
+    match vault.trash.remove(index).record {
+        TrashedRecord::ServiceType { service_type } => vault.service_types.push(service_type),
+        TrashedRecord::Service {
+            service,
+            linked_account_ids,
+        } => {
+            for account in &mut vault.accounts {
+                if linked_account_ids.contains(&account.id) {
+                    account.linked_services.push(service.id.clone());
+                    account.linked_services.sort();
+                    account.linked_services.dedup();
+                }
+            }
+            vault.services.push(service);
+        }
+        TrashedRecord::Account { account } => vault.accounts.push(account),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BackupSettings, Timestamps};
+    use std::collections::HashMap;
+
+    fn service_type(id: &str) -> ServiceType {
+        ServiceType {
+            id: id.to_string(),
+            name: id.to_string(),
+            icon: "key".to_string(),
+            fields: Vec::new(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn service(id: &str) -> Service {
+        Service {
+            id: id.to_string(),
+            service_type_id: "email".to_string(),
+            label: id.to_string(),
+            data: HashMap::from([("login".to_string(), id.to_string())]),
+            tags: Vec::new(),
+            field_history: HashMap::new(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn account(id: &str, linked_services: &[&str]) -> Account {
+        Account {
+            id: id.to_string(),
+            label: id.to_string(),
+            notes: String::new(),
+            tags: Vec::new(),
+            linked_services: linked_services.iter().map(|id| id.to_string()).collect(),
+            timestamps: Timestamps::default(),
+        }
+    }
+
+    fn vault() -> Vault {
+        let mut vault = Vault {
+            version: crate::migrations::CURRENT_VAULT_VERSION.to_string(),
+            service_types: vec![service_type("email"), service_type("bank")],
+            services: vec![service("s1"), service("s2"), service("s3")],
+            accounts: vec![account("a1", &["s1", "s2"]), account("a2", &["s1"])],
+            settings: Settings {
+                auto_lock_minutes: 5,
+                backup: BackupSettings::default(),
+                trash_retention_days: 30,
+            },
+            trash: Vec::new(),
+        };
+        Operation::TrashServices {
+            service_ids: vec!["s3".to_string()],
+            deleted_at: "2024-01-01T00:00:00+00:00".to_string(),
+        }
+        .apply(&mut vault)
+        .unwrap();
+        vault
+    }
+
+    /// The vault as JSON, with records sorted by id: undoing brings records
+    /// back at the end of their lists rather than where they were.
+    fn normalized(vault: &Vault) -> serde_json::Value {
+        let mut vault = vault.clone();
+        vault.service_types.sort_by(|a, b| a.id.cmp(&b.id));
+        vault.services.sort_by(|a, b| a.id.cmp(&b.id));
+        vault.accounts.sort_by(|a, b| a.id.cmp(&b.id));
+        serde_json::to_value(&vault).unwrap()
+    }
+
+    fn operations() -> Vec<Operation> {
+        let deleted_at = "2024-02-01T00:00:00+00:00".to_string();
+        let mut renamed = service("s1");
+        renamed.label = "Renamed".to_string();
+        vec![
+            Operation::UpdateSettings {
+                settings: Settings {
+                    auto_lock_minutes: 1,
+                    backup: BackupSettings::default(),
+                    trash_retention_days: 0,
+                },
+            },
+            Operation::AddServiceType {
+                service_type: service_type("chat"),
+            },
+            Operation::UpdateServiceType {
+                service_type: ServiceType {
+                    name: "Mail".to_string(),
+                    ..service_type("email")
+                },
+            },
+            Operation::DeleteServiceType {
+                service_type_id: "bank".to_string(),
+            },
+            Operation::TrashServiceType {
+                service_type_id: "bank".to_string(),
+                deleted_at: deleted_at.clone(),
+            },
+            Operation::AddService {
+                service: service("s9"),
+                account_id: Some("a1".to_string()),
+            },
+            Operation::AddServices {
+                services: vec![service("s8"), service("s9")],
+            },
+            Operation::UpdateService { service: renamed },
+            Operation::DeleteService {
+                service_id: "s1".to_string(),
+            },
+            Operation::DeleteServices {
+                service_ids: vec!["s1".to_string(), "s2".to_string()],
+            },
+            Operation::TrashServices {
+                service_ids: vec!["s1".to_string(), "missing".to_string()],
+                deleted_at: deleted_at.clone(),
+            },
+            Operation::AddAccount {
+                account: account("a9", &[]),
+            },
+            Operation::AddAccounts {
+                accounts: vec![account("a8", &["s1"]), account("a9", &[])],
+            },
+            Operation::UpdateAccount {
+                account: account("a1", &[]),
+            },
+            Operation::DeleteAccount {
+                account_id: "a1".to_string(),
+            },
+            Operation::TrashAccount {
+                account_id: "a1".to_string(),
+                deleted_at: deleted_at.clone(),
+            },
+            Operation::LinkServices {
+                account_id: "a2".to_string(),
+                service_ids: vec!["s2".to_string()],
+            },
+            Operation::SetLastUsed {
+                kind: RecordKind::Account,
+                id: "a2".to_string(),
+                last_used_at: Some(deleted_at.clone()),
+            },
+            Operation::RestoreFromTrash {
+                item: TrashKey {
+                    kind: RecordKind::Service,
+                    id: "s3".to_string(),
+                },
+            },
+            Operation::PurgeTrash {
+                items: vec![TrashKey {
+                    kind: RecordKind::Service,
+                    id: "s3".to_string(),
+                }],
+            },
+            Operation::Batch {
+                operations: vec![
+                    Operation::DeleteAccount {
+                        account_id: "a1".to_string(),
+                    },
+                    Operation::TrashServices {
+                        service_ids: vec!["s1".to_string()],
+                        deleted_at,
+                    },
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn inverses_undo_every_operation() {
+        let original = vault();
+        for operation in operations() {
+            let mut vault = original.clone();
+            let inverse = operation.inverse(&vault);
+            operation.apply(&mut vault).unwrap();
+            let changed = normalized(&vault);
+            assert_ne!(changed, normalized(&original), "{:?} changed nothing", operation);
+
+            inverse
+                .apply(&mut vault)
+                .unwrap_or_else(|e| panic!("undoing {:?} failed: {}", operation, e));
+            assert_eq!(normalized(&vault), normalized(&original), "undoing {:?}", operation);
+
+            // Redoing applies the operation itself again.
+            operation.apply(&mut vault).unwrap();
+            assert_eq!(normalized(&vault), changed, "redoing {:?}", operation);
+        }
+    }
+
+    #[test]
+    fn batches_apply_all_or_nothing() {
+        let mut vault = vault();
+        let batch = Operation::Batch {
+            operations: vec![
+                Operation::DeleteAccount {
+                    account_id: "a1".to_string(),
+                },
+                Operation::DeleteAccount {
+                    account_id: "missing".to_string(),
+                },
+            ],
+        };
+        assert!(matches!(batch.apply(&mut vault), Err(OperationError::AccountNotFound(_))));
+        assert_eq!(vault.accounts.len(), 2);
+    }
+
+    #[test]
+    fn undoing_an_edit_keeps_the_last_use() {
+        let mut vault = vault();
+        let edit = Operation::UpdateAccount {
+            account: Account {
+                label: "Renamed".to_string(),
+                ..account("a1", &["s1", "s2"])
+            },
+        };
+        let inverse = edit.inverse(&vault);
+        edit.apply(&mut vault).unwrap();
+        let used_at = Some("2024-03-01T00:00:00+00:00".to_string());
+        Operation::SetLastUsed {
+            kind: RecordKind::Account,
+            id: "a1".to_string(),
+            last_used_at: used_at.clone(),
+        }
+        .apply(&mut vault)
+        .unwrap();
+
+        inverse.keeping_last_used(&vault).apply(&mut vault).unwrap();
+        assert_eq!(vault.accounts[0].label, "a1");
+        assert_eq!(vault.accounts[0].timestamps.last_used_at, used_at);
+    }
+
+    #[test]
+    fn refuses_to_add_records_with_taken_ids() {
+        let mut vault = vault();
+        for operation in [
+            Operation::AddService {
+                service: service("s1"),
+                account_id: None,
+            },
+            Operation::AddServices {
+                services: vec![service("s9"), service("s2")],
+            },
+            Operation::AddServices {
+                services: vec![service("s9"), service("s9")],
+            },
+            Operation::AddAccount {
+                account: account("a1", &[]),
+            },
+            Operation::AddAccounts {
+                accounts: vec![account("a9", &[]), account("a2", &[])],
+            },
+        ] {
+            assert!(matches!(operation.apply(&mut vault), Err(OperationError::RecordExists(_))));
+        }
+        assert_eq!(normalized(&vault), normalized(&self::vault()));
+    }
+}
@@ -0,0 +1,67 @@
+//! `query.rs`: Sorting and filtering records by their timestamps, e.g. to find
+//! the accounts no one has touched in a while.
+
+use crate::journal;
+use crate::models::{Timestamped, Timestamps};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum TimestampField {
+    CreatedAt,
+    UpdatedAt,
+    LastUsedAt,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordQuery {
+    /// Sorts by this timestamp, oldest first unless `descending` is set.
+    /// Records without it, e.g. ones never used, count as oldest.
+    pub sort_by: Option<TimestampField>,
+    #[serde(default)]
+    pub descending: bool,
+    /// Only records that were neither changed nor used in this many days.
+    pub untouched_for_days: Option<u32>,
+}
+
+/// The records matching `query`, in the order it asks for. Records keep their
+/// vault order otherwise.
+pub fn query_records<T: Timestamped + Clone>(records: &[T], query: &RecordQuery, now: DateTime<Utc>) -> Vec<T> {
+    let mut matching: Vec<T> = match query.untouched_for_days {
+        Some(days) => {
+            let cutoff = now - chrono::Duration::days(i64::from(days));
+            records
+                .iter()
+                .filter(|record| match last_touched(record.timestamps()) {
+                    Some(touched) => touched < cutoff,
+                    None => true,
+                })
+                .cloned()
+                .collect()
+        }
+        None => records.to_vec(),
+    };
+
+    if let Some(field) = query.sort_by {
+        matching.sort_by_key(|record| timestamp(record.timestamps(), field));
+        if query.descending {
+            matching.reverse();
+        }
+    }
+    matching
+}
+
+fn timestamp(timestamps: &Timestamps, field: TimestampField) -> Option<DateTime<Utc>> {
+    match field {
+        TimestampField::CreatedAt => journal::parse_time(&timestamps.created_at),
+        TimestampField::UpdatedAt => journal::parse_time(&timestamps.updated_at),
+        TimestampField::LastUsedAt => timestamps.last_used_at.as_deref().and_then(journal::parse_time),
+    }
+}
+
+/// When the record was last changed or used, whichever is later.
+fn last_touched(timestamps: &Timestamps) -> Option<DateTime<Utc>> {
+    timestamp(timestamps, TimestampField::UpdatedAt).max(timestamp(timestamps, TimestampField::LastUsedAt))
+}
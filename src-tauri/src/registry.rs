@@ -0,0 +1,143 @@
+//! `registry.rs`: Keeps track of the named vaults the user has created or opened.
+
+use crate::storage::{self, StorageError};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const REGISTRY_FILE: &str = "vaults.json";
+/// Where vaults created without a chosen location are kept, inside the app data folder.
+const VAULTS_DIR: &str = "vaults";
+/// The only vault of builds before the registry, inside the app data folder.
+const DEFAULT_VAULT_FILE: &str = "vault.json";
+const DEFAULT_VAULT_NAME: &str = "Default";
+/// A file with this name next to the executable turns on portable mode.
+const PORTABLE_MARKER_FILE: &str = "portable";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultProfile {
+    pub id: String,
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Saved in plaintext next to the app's other data: it holds names and file
+/// locations only, never anything from inside a vault. Locations inside the
+/// app data folder are saved relative to it, so a portable copy keeps working
+/// when its drive is mounted somewhere else.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultRegistry {
+    pub vaults: Vec<VaultProfile>,
+    pub active_vault_id: Option<String>,
+}
+
+impl VaultRegistry {
+    /// Reads the registry from the app data folder. Without one, the vault
+    /// used by builds before the registry becomes the only, active entry.
+    /// The result always has an active vault.
+    pub fn load(app_data_dir: &Path) -> Result<VaultRegistry, StorageError> {
+        let file = app_data_dir.join(REGISTRY_FILE);
+        if file.exists() {
+            let contents = fs::read(&file)?;
+            let mut registry: VaultRegistry = serde_json::from_slice(&contents)?;
+            for profile in &mut registry.vaults {
+                if profile.path.is_relative() {
+                    profile.path = app_data_dir.join(&profile.path);
+                }
+            }
+            if registry.active().is_none() {
+                registry.active_vault_id = registry.vaults.first().map(|profile| profile.id.clone());
+            }
+            if registry.active_vault_id.is_some() {
+                return Ok(registry);
+            }
+        }
+
+        let profile = VaultProfile {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: DEFAULT_VAULT_NAME.to_string(),
+            path: app_data_dir.join(DEFAULT_VAULT_FILE),
+        };
+        info!("Registering {} as the default vault", profile.path.display());
+        Ok(VaultRegistry {
+            active_vault_id: Some(profile.id.clone()),
+            vaults: vec![profile],
+        })
+    }
+
+    pub fn save(&self, app_data_dir: &Path) -> Result<(), StorageError> {
+        let mut saved = self.clone();
+        for profile in &mut saved.vaults {
+            if let Ok(relative) = profile.path.strip_prefix(app_data_dir) {
+                profile.path = relative.to_path_buf();
+            }
+        }
+        let contents = serde_json::to_vec_pretty(&saved)?;
+        storage::write_atomically(&app_data_dir.join(REGISTRY_FILE), &contents)
+    }
+
+    pub fn active(&self) -> Option<&VaultProfile> {
+        let id = self.active_vault_id.as_ref()?;
+        self.find(id)
+    }
+
+    pub fn find(&self, id: &str) -> Option<&VaultProfile> {
+        self.vaults.iter().find(|profile| profile.id == id)
+    }
+
+    pub fn find_mut(&mut self, id: &str) -> Option<&mut VaultProfile> {
+        self.vaults.iter_mut().find(|profile| profile.id == id)
+    }
+
+    /// The entry for the vault file at `path`, if it is registered already.
+    pub fn find_by_path(&self, path: &Path) -> Option<&VaultProfile> {
+        self.vaults.iter().find(|profile| profile.path == path)
+    }
+
+    /// Registers a vault and returns its entry.
+    pub fn add(&mut self, name: String, path: PathBuf) -> VaultProfile {
+        let profile = VaultProfile {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            path,
+        };
+        self.vaults.push(profile.clone());
+        profile
+    }
+
+    /// Removes a vault from the registry. Its file is left alone.
+    pub fn remove(&mut self, id: &str) -> Option<VaultProfile> {
+        let index = self.vaults.iter().position(|profile| profile.id == id)?;
+        Some(self.vaults.remove(index))
+    }
+}
+
+/// The registry as managed Tauri state, with the folder it is saved in.
+pub struct RegistryState {
+    pub app_data_dir: PathBuf,
+    /// The app data folder is the executable's folder rather than the user's.
+    pub portable: bool,
+    pub registry: Mutex<VaultRegistry>,
+}
+
+/// In portable mode the registry, vaults and backups live next to the
+/// executable, e.g. on a USB drive, instead of in the user's app data folder.
+/// Returns that folder if the executable sits next to the marker file.
+pub fn portable_data_dir() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    let dir = exe.parent()?;
+    dir.join(PORTABLE_MARKER_FILE)
+        .is_file()
+        .then(|| dir.to_path_buf())
+}
+
+/// Default location for a new vault when the user doesn't pick one.
+pub fn default_vault_path(app_data_dir: &Path) -> PathBuf {
+    app_data_dir
+        .join(VAULTS_DIR)
+        .join(format!("{}.json", uuid::Uuid::new_v4()))
+}
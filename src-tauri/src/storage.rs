@@ -1,9 +1,18 @@
 //! `storage.rs`: Handles file system operations for the vault.
 
-use crate::crypto::{self, CryptoError, EncryptedVault, MasterPassword};
+use crate::backups;
+use crate::journal::{self, Journal};
+use crate::migrations::{self, MigrationError};
+use crate::crypto::{
+    self, CryptoError, EncryptedVault, KeyfileHash, MasterPassword, VaultKeys,
+};
 use crate::models::Vault;
+use crate::operations::OperationError;
 use crate::StoragePath;
-use std::fs;
+use ring::digest::{self, SHA256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use tauri::State;
 use log::{error, info}; // Import logging macros
 
@@ -19,6 +28,22 @@ pub enum StorageError {
     Crypto(String),
     #[error("Vault file not found at path: {0}")]
     NotFound(String),
+    #[error("Invalid keyfile: {0}")]
+    InvalidKeyfile(String),
+    #[error("Backup not found: {0}")]
+    BackupNotFound(String),
+    #[error("The vault is in use: {0}")]
+    VaultInUse(String),
+    #[error("The vault file was changed outside this session since it was loaded")]
+    ChangedOnDisk,
+    #[error("{0}")]
+    Migration(String),
+    #[error("Change journal error: {0}")]
+    Journal(String),
+    #[error("The vault history doesn't reach back to {0}")]
+    HistoryUnavailable(String),
+    #[error("The vault's backups from {0} were encrypted with a key that was replaced since")]
+    HistoryBeforeKeyRotation(String),
 }
 
 impl From<std::io::Error> for StorageError {
@@ -39,70 +64,430 @@ impl From<CryptoError> for StorageError {
     }
 }
 
+impl From<MigrationError> for StorageError {
+    fn from(err: MigrationError) -> Self {
+        StorageError::Migration(err.to_string())
+    }
+}
+
+impl From<OperationError> for StorageError {
+    fn from(err: OperationError) -> Self {
+        StorageError::Journal(err.to_string())
+    }
+}
+
+/// SHA-256 of the vault file as this session last read or wrote it, used to
+/// notice changes made by someone else in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHash([u8; 32]);
+
+impl FileHash {
+    pub(crate) fn of(contents: &[u8]) -> Self {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(digest::digest(&SHA256, contents).as_ref());
+        FileHash(hash)
+    }
+
+    pub(crate) fn to_hex(self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// A decrypted vault together with the keys needed to save it again.
+pub struct LoadedVault {
+    pub vault: Vault,
+    pub keys: VaultKeys,
+    /// The journal of changes since the vault file was last written in full,
+    /// already applied to `vault`.
+    pub journal: Journal,
+    /// The envelope uses an older format or KDF settings that should be replaced.
+    pub needs_upgrade: bool,
+    /// The password slot should be re-wrapped with the default KDF settings.
+    pub kdf_needs_upgrade: bool,
+    /// The envelope predates data keys, so the one in `keys` was generated on
+    /// load and exists nowhere else until the vault is saved.
+    pub new_data_key: bool,
+    /// The vault JSON was upgraded from an older schema and hasn't been saved yet.
+    pub migrated: bool,
+}
+
 // --- Public Functions ---
 
 pub fn vault_exists(path: &State<StoragePath>) -> bool {
-    path.0.exists()
+    path.get().exists()
 }
 
-pub fn save_vault(
-    path: &State<StoragePath>,
-    vault: &Vault,
-    password: &MasterPassword,
-) -> Result<(), StorageError> {
-    info!("Attempting to save vault to {}", path.0.display());
+/// Reads a keyfile and returns the hash of its contents.
+pub fn read_keyfile(keyfile_path: &Path) -> Result<KeyfileHash, StorageError> {
+    let contents = fs::read(keyfile_path).map_err(|e| {
+        error!("Failed to read keyfile {}: {}", keyfile_path.display(), e);
+        StorageError::InvalidKeyfile(e.to_string())
+    })?;
+    if contents.is_empty() {
+        return Err(StorageError::InvalidKeyfile("the file is empty".to_string()));
+    }
+    Ok(KeyfileHash::from_contents(&contents))
+}
+
+fn read_envelope(path: &State<StoragePath>) -> Result<(EncryptedVault, FileHash), StorageError> {
+    read_envelope_file(&path.get())
+}
+
+fn read_envelope_file(vault_path: &Path) -> Result<(EncryptedVault, FileHash), StorageError> {
+    if !vault_path.exists() {
+        error!("Vault file not found at {}", vault_path.display());
+        return Err(StorageError::NotFound(vault_path.display().to_string()));
+    }
+
+    let encrypted_json = fs::read_to_string(vault_path).map_err(|e| {
+        error!("Failed to read vault file: {}", e);
+        StorageError::from(e)
+    })?;
+    let encrypted_vault: EncryptedVault = serde_json::from_str(&encrypted_json).map_err(|e| {
+        error!("Failed to deserialize encrypted vault from JSON: {}", e);
+        StorageError::from(e)
+    })?;
+    Ok((encrypted_vault, FileHash::of(encrypted_json.as_bytes())))
+}
+
+/// Deserializes decrypted vault JSON, migrating it from an older schema first.
+/// Before a migration the file at `backup_path` is backed up, whatever the
+/// backup settings.
+fn parse_vault(backup_path: Option<&Path>, json: &[u8]) -> Result<(Vault, bool), StorageError> {
+    let mut value: serde_json::Value = serde_json::from_slice(json).map_err(|e| {
+        error!("Failed to deserialize vault from decrypted JSON: {}", e);
+        StorageError::from(e)
+    })?;
+
+    let version = value["version"].as_str().unwrap_or("unknown").to_string();
+    let migrated = migrations::migrate(&mut value).map_err(|e| {
+        error!("Failed to migrate vault: {}", e);
+        StorageError::from(e)
+    })?;
+    if migrated {
+        info!(
+            "Vault migrated from schema {} to {}",
+            version,
+            migrations::CURRENT_VAULT_VERSION
+        );
+    }
+    if let Some(vault_path) = backup_path.filter(|_| migrated) {
+        // Nothing is written yet, but the next save replaces the old file.
+        let backup = backups::back_up(vault_path)?;
+        info!("Vault file from before the migration kept at {}", backup.display());
+    }
+
+    let vault: Vault = serde_json::from_value(value).map_err(|e| {
+        error!("Failed to deserialize vault from decrypted JSON: {}", e);
+        StorageError::from(e)
+    })?;
+    Ok((vault, migrated))
+}
+
+/// Opens the vault file's journal and applies its entries to `vault`.
+fn replay_journal(
+    vault_path: &Path,
+    file_hash: FileHash,
+    vault: &mut Vault,
+    keys: &VaultKeys,
+) -> Result<Journal, StorageError> {
+    let (journal, entries) = Journal::open(vault_path, file_hash, keys).map_err(|e| {
+        error!("Failed to read the change journal: {}", e);
+        e
+    })?;
+    for entry in &entries {
+        entry.operation.apply(vault).map_err(|e| {
+            error!("Failed to apply journal entry {}: {}", entry.seq, e);
+            StorageError::from(e)
+        })?;
+    }
+    Ok(journal)
+}
+
+fn encrypt_to_json(vault: &Vault, keys: &VaultKeys) -> Result<String, StorageError> {
     let vault_json = serde_json::to_string(vault).map_err(|e| {
         error!("Failed to serialize vault: {}", e);
         StorageError::from(e)
     })?;
 
-    let encrypted_vault = crypto::encrypt_vault(vault_json.as_bytes(), password).map_err(|e| {
+    let encrypted_vault = crypto::encrypt_vault(vault_json.as_bytes(), keys).map_err(|e| {
         error!("Failed to encrypt vault: {}", e);
         StorageError::from(e)
     })?;
-    let encrypted_json = serde_json::to_string_pretty(&encrypted_vault).map_err(|e| {
+    serde_json::to_string_pretty(&encrypted_vault).map_err(|e| {
         error!("Failed to serialize encrypted vault: {}", e);
         StorageError::from(e)
+    })
+}
+
+/// Saves each recovery share to its own file in `directory`, so they can be
+/// handed to different people.
+pub fn write_recovery_shares(
+    directory: &Path,
+    shares: &[MasterPassword],
+) -> Result<Vec<PathBuf>, StorageError> {
+    let mut files = Vec::with_capacity(shares.len());
+    for (index, share) in shares.iter().enumerate() {
+        let file = directory.join(format!(
+            "accman-recovery-share-{}-of-{}.txt",
+            index + 1,
+            shares.len()
+        ));
+        fs::write(&file, format!("{}\n", share.0)).map_err(|e| {
+            error!("Failed to write recovery share to {}: {}", file.display(), e);
+            StorageError::from(e)
+        })?;
+        files.push(file);
+    }
+    info!("Saved {} recovery shares to {}", files.len(), directory.display());
+    Ok(files)
+}
+
+pub fn read_recovery_share(share_path: &Path) -> Result<String, StorageError> {
+    let share = fs::read_to_string(share_path).map_err(|e| {
+        error!("Failed to read recovery share {}: {}", share_path.display(), e);
+        StorageError::from(e)
     })?;
+    Ok(share.trim().to_string())
+}
 
-    fs::write(&path.0, encrypted_json).map_err(|e| {
+/// Whether the vault on disk was encrypted with a keyfile, so the unlock
+/// screen knows to ask for one.
+pub fn vault_requires_keyfile(path: &State<StoragePath>) -> Result<bool, StorageError> {
+    Ok(read_envelope(path)?.0.keyfile)
+}
+
+/// Encrypts and writes the whole vault, which also compacts its journal: the
+/// new file holds every change so far and the journal starts over. With
+/// `expected`, the save is refused if the file or its journal no longer match
+/// what this session last read or wrote. Returns the new, empty journal.
+pub fn save_vault(
+    path: &State<StoragePath>,
+    vault: &Vault,
+    keys: &VaultKeys,
+    expected: Option<&Journal>,
+) -> Result<Journal, StorageError> {
+    let vault_path = path.get();
+    info!("Attempting to save vault to {}", vault_path.display());
+    if let Some(expected) = expected {
+        if let Err(e) = expected.check_unchanged(&vault_path) {
+            error!("Vault file was changed on disk; refusing to overwrite it.");
+            return Err(e);
+        }
+    }
+
+    let encrypted_json = encrypt_to_json(vault, keys)?;
+    backups::back_up_vault(&vault_path, &vault.settings.backup)?;
+    write_atomically(&vault_path, encrypted_json.as_bytes()).map_err(|e| {
         error!("Failed to write vault to file: {}", e);
-        StorageError::from(e)
+        e
     })?;
+    let journal = Journal::create(&vault_path, FileHash::of(encrypted_json.as_bytes()))?;
     
     info!("Vault saved successfully.");
+    Ok(journal)
+}
+
+/// Replaces `path` with `contents` so that a crash or full disk leaves either
+/// the old or the new file, never a truncated one. The data goes to a temp file
+/// next to `path`, which is fsynced and renamed over it; the directory is then
+/// fsynced so the rename itself is durable.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), StorageError> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().ok_or_else(|| {
+        StorageError::Io(format!("{} is not a file path", path.display()))
+    })?;
+    let mut temp_name = file_name.to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = directory.join(temp_name);
+
+    if let Err(e) = write_and_rename(&temp_path, path, contents) {
+        // The original file is untouched; just clean up the partial temp file.
+        let _ = fs::remove_file(&temp_path);
+        return Err(StorageError::from(e));
+    }
+
+    sync_directory(directory)
+}
+
+fn write_and_rename(temp_path: &Path, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(temp_path)?;
+    temp_file.write_all(contents)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+    fs::rename(temp_path, path)
+}
+
+/// Directories can't be opened for syncing on Windows, so there only the
+/// file contents are synced.
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> Result<(), StorageError> {
+    fs::File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> Result<(), StorageError> {
     Ok(())
 }
 
+/// Loads and decrypts the vault. Envelopes written by older builds are accepted.
 pub fn load_vault(
     path: &State<StoragePath>,
     password: &MasterPassword,
-) -> Result<Vault, StorageError> {
-    info!("Attempting to load vault from {}", path.0.display());
-    if !vault_exists(path) {
-        error!("Vault file not found at {}", path.0.display());
-        return Err(StorageError::NotFound(path.0.display().to_string()));
-    }
+    keyfile: Option<&KeyfileHash>,
+) -> Result<LoadedVault, StorageError> {
+    info!("Attempting to load vault from {}", path.get().display());
+    let (encrypted_vault, file_hash) = read_envelope(path)?;
 
-    let encrypted_json = fs::read_to_string(&path.0).map_err(|e| {
-        error!("Failed to read vault file: {}", e);
+    let needs_upgrade = encrypted_vault.needs_upgrade();
+    let kdf_needs_upgrade = encrypted_vault.kdf_needs_upgrade();
+    let new_data_key = encrypted_vault.wrapped_key.is_none();
+    let (decrypted_json_bytes, keys) = crypto::decrypt_vault(encrypted_vault, password, keyfile).map_err(|e| {
+        error!("Failed to decrypt vault: {}", e);
         StorageError::from(e)
     })?;
-    let encrypted_vault: EncryptedVault = serde_json::from_str(&encrypted_json).map_err(|e| {
-        error!("Failed to deserialize encrypted vault from JSON: {}", e);
+
+    let (mut vault, migrated) = parse_vault(Some(&path.get()), &decrypted_json_bytes)?;
+    let journal = replay_journal(&path.get(), file_hash, &mut vault, &keys)?;
+
+    info!("Vault loaded and decrypted successfully.");
+    Ok(LoadedVault {
+        vault,
+        keys,
+        journal,
+        needs_upgrade,
+        kdf_needs_upgrade,
+        new_data_key,
+        migrated,
+    })
+}
+
+/// Loads and decrypts the vault with its recovery key, for when the master
+/// password has been forgotten. The returned keys are wrapped under the new
+/// password; the vault still has to be saved for it to take effect.
+pub fn load_vault_with_recovery_key(
+    path: &State<StoragePath>,
+    recovery_key: &str,
+    new_password: &MasterPassword,
+    new_keyfile: Option<&KeyfileHash>,
+) -> Result<LoadedVault, StorageError> {
+    info!("Attempting to recover vault from {}", path.get().display());
+    let (encrypted_vault, file_hash) = read_envelope(path)?;
+    let needs_upgrade = encrypted_vault.needs_upgrade();
+    let kdf_needs_upgrade = encrypted_vault.kdf_needs_upgrade();
+
+    let (decrypted_json_bytes, keys) = crypto::decrypt_vault_with_recovery_key(
+        encrypted_vault,
+        recovery_key,
+        new_password,
+        new_keyfile,
+    )
+    .map_err(|e| {
+        error!("Failed to decrypt vault with recovery key: {}", e);
         StorageError::from(e)
     })?;
 
-    let decrypted_json_bytes = crypto::decrypt_vault(encrypted_vault, password).map_err(|e| {
-        error!("Failed to decrypt vault: {}", e);
+    let (mut vault, migrated) = parse_vault(Some(&path.get()), &decrypted_json_bytes)?;
+    let journal = replay_journal(&path.get(), file_hash, &mut vault, &keys)?;
+
+    info!("Vault decrypted with recovery key.");
+    Ok(LoadedVault {
+        vault,
+        keys,
+        journal,
+        needs_upgrade,
+        kdf_needs_upgrade,
+        new_data_key: false,
+        migrated,
+    })
+}
+
+/// Reads the vault file again with the keys of the current session, e.g. after
+/// someone else saved it. Fails if the file is no longer encrypted under the
+/// same data key and password.
+pub fn reload_vault(path: &State<StoragePath>, keys: &VaultKeys) -> Result<LoadedVault, StorageError> {
+    info!("Attempting to reload vault from {}", path.get().display());
+    let (encrypted_vault, file_hash) = read_envelope(path)?;
+    let needs_upgrade = encrypted_vault.needs_upgrade();
+    let kdf_needs_upgrade = encrypted_vault.kdf_needs_upgrade();
+
+    let (decrypted_json_bytes, keys) = crypto::decrypt_vault_with_keys(encrypted_vault, keys).map_err(|e| {
+        error!("Failed to decrypt reloaded vault: {}", e);
         StorageError::from(e)
     })?;
 
-    let vault: Vault = serde_json::from_slice(&decrypted_json_bytes).map_err(|e| {
-        error!("Failed to deserialize vault from decrypted JSON: {}", e);
+    let (mut vault, migrated) = parse_vault(Some(&path.get()), &decrypted_json_bytes)?;
+    let journal = replay_journal(&path.get(), file_hash, &mut vault, &keys)?;
+
+    info!("Vault reloaded from disk.");
+    Ok(LoadedVault {
+        vault,
+        keys,
+        journal,
+        needs_upgrade,
+        kdf_needs_upgrade,
+        new_data_key: false,
+        migrated,
+    })
+}
+
+/// Writes a copy of the vault to `file`, encrypted under `keys`. The file has
+/// the same format as the vault file itself, so it can be opened on its own.
+pub fn export_vault(file: &Path, vault: &Vault, keys: &VaultKeys) -> Result<(), StorageError> {
+    info!("Exporting vault to {}", file.display());
+    let encrypted_json = encrypt_to_json(vault, keys)?;
+    write_atomically(file, encrypted_json.as_bytes()).map_err(|e| {
+        error!("Failed to write vault export: {}", e);
+        e
+    })?;
+    info!("Vault exported successfully.");
+    Ok(())
+}
+
+/// Reads and decrypts an exported vault, or any other vault file, with its
+/// own password. Changes journaled next to the file are applied, as when the
+/// vault is loaded, and older schemas are migrated; the files are left alone.
+pub fn read_exported_vault(
+    file: &Path,
+    password: &MasterPassword,
+    keyfile: Option<&KeyfileHash>,
+) -> Result<Vault, StorageError> {
+    info!("Reading exported vault from {}", file.display());
+    let (encrypted_vault, file_hash) = read_envelope_file(file)?;
+
+    let (decrypted_json_bytes, keys) = crypto::decrypt_vault(encrypted_vault, password, keyfile).map_err(|e| {
+        error!("Failed to decrypt exported vault: {}", e);
         StorageError::from(e)
     })?;
-    
-    info!("Vault loaded and decrypted successfully.");
+
+    let (mut vault, _) = parse_vault(None, &decrypted_json_bytes)?;
+    if let Some(contents) = journal::read_journal(&journal::journal_path(file), file_hash, &keys)? {
+        for entry in &contents.entries {
+            entry.operation.apply(&mut vault)?;
+        }
+        info!("Applied {} journal entries of the exported vault", contents.entries.len());
+    }
     Ok(vault)
 }
+
+/// Reads and decrypts an earlier copy of the vault file, such as a backup,
+/// with the session's data key. Older schemas are migrated; the file itself
+/// is left alone. Also returns the file's hash, which ties it to its journal.
+pub fn read_snapshot(file: &Path, keys: &VaultKeys) -> Result<(Vault, FileHash), StorageError> {
+    let (encrypted_vault, file_hash) = read_envelope_file(file)?;
+    let decrypted_json_bytes = crypto::decrypt_vault_with_data_key(&encrypted_vault, keys).map_err(|e| {
+        error!("Failed to decrypt {}: {}", file.display(), e);
+        StorageError::from(e)
+    })?;
+
+    let (vault, _) = parse_vault(None, &decrypted_json_bytes)?;
+    Ok((vault, file_hash))
+}
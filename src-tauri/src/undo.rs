@@ -0,0 +1,78 @@
+//! `undo.rs`: The changes made to the open vault this session, so they can be
+//! undone and redone.
+//!
+//! Each change is kept together with the operation that reverts it, worked
+//! out before the change was applied. Undoing and redoing are changes like
+//! any other: they are applied and journaled as operations. Replacing the
+//! vault as a whole, e.g. by importing or reloading it, forgets the history.
+
+use crate::operations::Operation;
+use serde::Serialize;
+
+/// How many changes can be undone.
+pub const MAX_UNDO_STEPS: usize = 100;
+
+/// One change and the operation that reverts it.
+#[derive(Debug, Clone)]
+pub struct UndoStep {
+    pub operation: Operation,
+    pub inverse: Operation,
+}
+
+#[derive(Debug, Default)]
+pub struct UndoHistory {
+    undo: Vec<UndoStep>,
+    redo: Vec<UndoStep>,
+}
+
+/// How many changes can be undone and redone, for the frontend.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UndoState {
+    pub undo_steps: usize,
+    pub redo_steps: usize,
+}
+
+impl UndoHistory {
+    /// Records a change that was just made. Changes undone before it can no
+    /// longer be redone.
+    pub fn record(&mut self, operation: Operation, inverse: Operation) {
+        self.redo.clear();
+        self.push_undo(UndoStep { operation, inverse });
+    }
+
+    /// The most recent change, to be undone. Pass it to `push_redo` once it
+    /// is, or back to `push_undo` if that failed.
+    pub fn pop_undo(&mut self) -> Option<UndoStep> {
+        self.undo.pop()
+    }
+
+    /// The most recently undone change, to be made again. Pass it to
+    /// `push_undo` once it is, or back to `push_redo` if that failed.
+    pub fn pop_redo(&mut self) -> Option<UndoStep> {
+        self.redo.pop()
+    }
+
+    pub fn push_undo(&mut self, step: UndoStep) {
+        self.undo.push(step);
+        if self.undo.len() > MAX_UNDO_STEPS {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn push_redo(&mut self, step: UndoStep) {
+        self.redo.push(step);
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    pub fn state(&self) -> UndoState {
+        UndoState {
+            undo_steps: self.undo.len(),
+            redo_steps: self.redo.len(),
+        }
+    }
+}
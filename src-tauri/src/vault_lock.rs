@@ -0,0 +1,147 @@
+//! `vault_lock.rs`: Advisory lock that keeps a vault open in one place at a time.
+
+use crate::storage::StorageError;
+use fs4::{FileExt, TryLockError};
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Who holds the lock. It is written into the lock file so another instance
+/// can tell the user where the vault is open, and cleared on release.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LockOwner {
+    host: String,
+    pid: u32,
+    /// RFC 3339 timestamp.
+    acquired_at: String,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        LockOwner {
+            host: current_host(),
+            pid: std::process::id(),
+            acquired_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "opened on {} by process {} at {}",
+            self.host, self.pid, self.acquired_at
+        )
+    }
+}
+
+/// Held for as long as the vault is unlocked. The OS lock on the lock file
+/// keeps other instances on this machine out and goes away with the process;
+/// the owner record also covers copies of the vault shared through a sync
+/// client, which OS locks don't reach. Dropping it releases both.
+pub struct VaultLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl VaultLock {
+    pub fn acquire(vault_path: &Path) -> Result<VaultLock, StorageError> {
+        let path = lock_path(vault_path);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        try_lock(&mut file)?;
+
+        if let Some(owner) = read_owner(&mut file) {
+            if owner.host != current_host() {
+                let _ = FileExt::unlock(&file);
+                return Err(StorageError::VaultInUse(owner.describe()));
+            }
+            // Same machine, yet nobody holds the OS lock: the owner exited
+            // without releasing it, e.g. after a crash.
+            warn!("Taking over stale vault lock ({})", owner.describe());
+        }
+
+        let mut lock = VaultLock { file, path };
+        lock.write_owner(Some(&LockOwner::current()))?;
+        info!("Vault lock acquired at {}", lock.path.display());
+        Ok(lock)
+    }
+
+    fn write_owner(&mut self, owner: Option<&LockOwner>) -> Result<(), StorageError> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        if let Some(owner) = owner {
+            serde_json::to_writer(&mut self.file, owner)?;
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        // The file itself stays; removing it could let two instances lock
+        // different files under the same name.
+        if let Err(e) = self.write_owner(None) {
+            warn!("Failed to clear vault lock owner: {}", e);
+        }
+        let _ = FileExt::unlock(&self.file);
+        info!("Vault lock released at {}", self.path.display());
+    }
+}
+
+/// Clears a lock left by an instance on another machine that can no longer
+/// release it. Fails if the vault is open in another instance on this one.
+pub fn break_lock(vault_path: &Path) -> Result<(), StorageError> {
+    let path = lock_path(vault_path);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    try_lock(&mut file)?;
+
+    if let Some(owner) = read_owner(&mut file) {
+        warn!("Breaking vault lock ({})", owner.describe());
+    }
+    let mut lock = VaultLock { file, path };
+    lock.write_owner(None)
+}
+
+/// Takes the OS lock on the lock file without waiting for it.
+fn try_lock(file: &mut File) -> Result<(), StorageError> {
+    match FileExt::try_lock(file) {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => {
+            // Windows doesn't allow reading a locked file, so the owner may be unknown.
+            let owner = read_owner(file).map(|owner| owner.describe());
+            Err(StorageError::VaultInUse(
+                owner.unwrap_or_else(|| "opened by another AccMan instance".to_string()),
+            ))
+        }
+        Err(TryLockError::Error(e)) => Err(StorageError::from(e)),
+    }
+}
+
+fn lock_path(vault_path: &Path) -> PathBuf {
+    let mut file_name = vault_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".lock");
+    vault_path.with_file_name(file_name)
+}
+
+/// The owner recorded in the lock file, if there is one and it can be read.
+fn read_owner(file: &mut File) -> Option<LockOwner> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn current_host() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
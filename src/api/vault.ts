@@ -1,6 +1,6 @@
 import { invoke } from '@tauri-apps/api/core';
 import { toast } from 'sonner';
-import type { Vault, ServiceType, Service, Account, Settings, BulkCreateRequest } from '@/types';
+import type { Vault, ServiceType, Service, Account, Settings, BulkCreateRequest, UnlockResult, KdfAlgorithm, KdfCalibration, EncryptionSettings, Cipher, CreateVaultResult, RecoveryShares, BackupInfo, VaultProfile, VaultRegistry, ImportMode, ImportResult, MergePreview, MergeDecision, HistoryEntry, HistoryPreview, RecordKind, FieldValue, TrashedItem, TrashKey, UndoState, RecordQuery } from '@/types';
 import i18n from '@/i18n';
 
 /**
@@ -23,7 +23,11 @@ async function callApi<T>(
     }
     return result;
   } catch (error: any) {
-    const errorMessage = typeof error === 'string' ? error : (error.message || 'An unknown error occurred');
+    const errorMessage = typeof error === 'string'
+      ? error
+      : error?.HistoryBeforeKeyRotation !== undefined
+        ? i18n.t('api.error.history_before_key_rotation')
+        : (error.message || 'An unknown error occurred');
     console.error(`API Error on command '${command}':`, errorMessage);
     toast.error(`${i18n.t('api.error.prefix')}: ${errorMessage}`);
     throw new Error(errorMessage);
@@ -33,41 +37,144 @@ async function callApi<T>(
 export const vaultApi = {
   vaultExists: () => callApi<boolean>('vault_exists'),
 
+  vaultRequiresKeyfile: () => callApi<boolean>('vault_requires_keyfile'),
+
   getDefaultServiceTypesList: () => callApi<ServiceType[]>('get_default_service_types_list'),
 
-  createVault: (password: string, settings: Settings, selectedServiceTypeIds: string[]) =>
-    callApi<Vault>('create_vault', { password, settings, selectedServiceTypeIds }, 'api.success.vault_created'),
+  calibrateKdf: (targetMs: number, algorithm?: KdfAlgorithm) =>
+    callApi<KdfCalibration>('calibrate_kdf', { targetMs, algorithm }),
+
+  createVault: (password: string, settings: Settings, selectedServiceTypeIds: string[], encryption?: EncryptionSettings, keyfilePath?: string, withRecoveryKey?: boolean) =>
+    callApi<CreateVaultResult>('create_vault', { password, settings, selectedServiceTypeIds, encryption, keyfilePath, withRecoveryKey }, 'api.success.vault_created'),
 
-  unlockVault: (password: string) =>
-    callApi<Vault>('unlock_vault', { password }),
+  unlockVault: (password: string, keyfilePath?: string) =>
+    callApi<UnlockResult>('unlock_vault', { password, keyfilePath }),
 
   lockVault: () =>
     callApi<void>('lock_vault'),
 
+  breakVaultLock: () =>
+    callApi<void>('break_vault_lock'),
+
+  reloadVault: () =>
+    callApi<Vault>('reload_vault', {}, 'api.success.vault_reloaded'),
+
+  recoverVault: (recoveryKey: string, newPassword: string, keyfilePath?: string) =>
+    callApi<Vault>('recover_vault', { recoveryKey, newPassword, keyfilePath }, 'api.success.password_changed'),
+
+  recoverVaultWithShares: (shares: string[], newPassword: string, sharePaths?: string[], keyfilePath?: string) =>
+    callApi<Vault>('recover_vault_with_shares', { shares, sharePaths, newPassword, keyfilePath }, 'api.success.password_changed'),
+
+  generateRecoveryKey: () =>
+    callApi<string>('generate_recovery_key'),
+
+  generateRecoveryShares: (threshold: number, shareCount: number, outputDir?: string) =>
+    callApi<RecoveryShares>('generate_recovery_shares', { threshold, shareCount, outputDir }),
+
+  // Backups
+  listBackups: () =>
+    callApi<BackupInfo[]>('list_backups'),
+  restoreBackup: (fileName: string) =>
+    callApi<void>('restore_backup', { fileName }, 'api.success.backup_restored'),
+
+  // Export / Import
+  exportVaultBackup: (filePath: string, exportPassword?: string) =>
+    callApi<void>('export_vault_backup', { filePath, exportPassword }, 'api.success.vault_exported'),
+  importVaultBackup: (filePath: string, password: string, mode: ImportMode, keyfilePath?: string) =>
+    callApi<ImportResult>('import_vault_backup', { filePath, password, keyfilePath, mode }),
+
+  // Merge
+  previewVaultMerge: (filePath: string, password: string, keyfilePath?: string) =>
+    callApi<MergePreview>('preview_vault_merge', { filePath, password, keyfilePath }),
+  applyVaultMerge: (decisions: MergeDecision[]) =>
+    callApi<ImportResult>('apply_vault_merge', { decisions }, 'api.success.vault_merged'),
+  cancelVaultMerge: () =>
+    callApi<void>('cancel_vault_merge'),
+
+  // History
+  listVaultHistory: () =>
+    callApi<HistoryEntry[]>('list_vault_history'),
+  previewVaultAt: (time: string) =>
+    callApi<HistoryPreview>('preview_vault_at', { time }),
+  restoreHistoryRecord: (time: string, kind: RecordKind, id: string) =>
+    callApi<Vault>('restore_history_record', { time, kind, id }, 'api.success.record_restored'),
+  restoreVaultAt: (time: string) =>
+    callApi<Vault>('restore_vault_at', { time }, 'api.success.vault_restored'),
+
+  // Record queries
+  listAccounts: (query?: RecordQuery) =>
+    callApi<Account[]>('list_accounts', { query }),
+  listServices: (query?: RecordQuery) =>
+    callApi<Service[]>('list_services', { query }),
+  listServiceTypes: (query?: RecordQuery) =>
+    callApi<ServiceType[]>('list_service_types', { query }),
+  markRecordUsed: (kind: RecordKind, id: string) =>
+    callApi<string>('mark_record_used', { kind, id }),
+
+  // Undo
+  undo: (steps?: number) =>
+    callApi<Vault>('undo', { steps }, 'api.success.undone'),
+  redo: (steps?: number) =>
+    callApi<Vault>('redo', { steps }, 'api.success.redone'),
+  getUndoState: () =>
+    callApi<UndoState>('get_undo_state'),
+
+  // Trash
+  listTrash: () =>
+    callApi<TrashedItem[]>('list_trash'),
+  restoreFromTrash: (kind: RecordKind, id: string) =>
+    callApi<Vault>('restore_from_trash', { kind, id }, 'api.success.item_restored'),
+  purgeTrash: (items: TrashKey[]) =>
+    callApi<void>('purge_trash', { items }, 'api.success.trash_purged'),
+  emptyTrash: () =>
+    callApi<void>('empty_trash', {}, 'api.success.trash_emptied'),
+
+  // Vault registry
+  listVaults: () =>
+    callApi<VaultRegistry>('list_vaults'),
+  isPortableMode: () =>
+    callApi<boolean>('is_portable_mode'),
+  createVaultProfile: (name: string, vaultPath?: string) =>
+    callApi<VaultProfile>('create_vault_profile', { name, vaultPath }),
+  openVaultProfile: (name: string, vaultPath: string) =>
+    callApi<VaultProfile>('open_vault_profile', { name, vaultPath }, 'api.success.vault_opened'),
+  openVaultFile: (vaultPath: string) =>
+    callApi<VaultProfile>('open_vault_file', { vaultPath }, 'api.success.vault_opened'),
+  switchVault: (id: string) =>
+    callApi<VaultProfile>('switch_vault', { id }),
+  renameVault: (id: string, name: string) =>
+    callApi<VaultProfile>('rename_vault', { id, name }),
+  forgetVault: (id: string) =>
+    callApi<void>('forget_vault', { id }, 'api.success.vault_forgotten'),
+
   // Settings
   updateSettings: (settings: Settings) =>
     callApi<void>('update_settings', { settings }),
-  changePassword: (oldPassword: string, newPassword: string) =>
-    callApi<void>('change_master_password', { oldPassword, newPassword }, 'api.success.password_changed'),
+  changePassword: (oldPassword: string, newPassword: string, cipher?: Cipher, keyfilePath?: string) =>
+    callApi<void>('change_master_password', { oldPassword, newPassword, cipher, keyfilePath }, 'api.success.password_changed'),
 
   // Service Types
   addServiceType: (serviceType: ServiceType) =>
-    callApi<void>('add_service_type', { serviceType }, 'api.success.service_type_added'),
+    callApi<ServiceType>('add_service_type', { serviceType }, 'api.success.service_type_added'),
 
   updateServiceType: (serviceType: ServiceType) =>
-    callApi<void>('update_service_type', { serviceType }, 'api.success.service_type_updated'),
+    callApi<ServiceType>('update_service_type', { serviceType }, 'api.success.service_type_updated'),
 
   deleteServiceType: (serviceTypeId: string) =>
     callApi<void>('delete_service_type', { serviceTypeId }, 'api.success.service_type_deleted'),
 
   // Services
   addService: (service: Service, accountId?: string) =>
-    callApi<void>('add_service', { service, accountId }, 'api.success.service_added'),
+    callApi<Service>('add_service', { service, accountId }, 'api.success.service_added'),
   addServices: (services: Service[]) =>
-    callApi<void>('add_services', { services }),
+    callApi<Service[]>('add_services', { services }),
   
   updateService: (service: Service) =>
-    callApi<void>('update_service', { service }, 'api.success.service_updated'),
+    callApi<Service>('update_service', { service }, 'api.success.service_updated'),
+  getServiceFieldHistory: (serviceId: string) =>
+    callApi<Record<string, FieldValue[]>>('get_service_field_history', { serviceId }),
+  restoreFieldValue: (serviceId: string, fieldKey: string, replacedAt: string) =>
+    callApi<Service>('restore_field_value', { serviceId, fieldKey, replacedAt }, 'api.success.field_restored'),
 
   deleteService: (serviceId: string) =>
     callApi<void>('delete_service', { serviceId }, 'api.success.service_deleted'),
@@ -77,16 +184,16 @@ export const vaultApi = {
 
   // Accounts
   addAccount: (account: Account) =>
-    callApi<void>('add_account', { account }, 'api.success.account_added'),
+    callApi<Account>('add_account', { account }, 'api.success.account_added'),
 
   updateAccount: (account: Account) =>
-    callApi<void>('update_account', { account }, 'api.success.account_updated'),
+    callApi<Account>('update_account', { account }, 'api.success.account_updated'),
 
   deleteAccount: (accountId: string) =>
     callApi<void>('delete_account', { accountId }, 'api.success.account_deleted'),
     
   linkServicesToAccount: (accountId: string, serviceIds: string[]) =>
-    callApi<void>('link_services_to_account', { accountId, serviceIds }, 'api.success.services_linked'),
+    callApi<Account>('link_services_to_account', { accountId, serviceIds }, 'api.success.services_linked'),
 
   bulkCreateAccounts: (request: BulkCreateRequest) =>
     callApi<void>('bulk_create_accounts', { request: request as unknown as Record<string, unknown> }, 'api.success.bulk_accounts_created'),
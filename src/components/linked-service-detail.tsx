@@ -15,7 +15,7 @@ interface LinkedServiceDetailProps {
 }
 
 export function LinkedServiceDetail({ service }: LinkedServiceDetailProps) {
-  const { vault } = useVaultStore()
+  const { vault, markServiceUsed } = useVaultStore()
   const { t } = useTranslation();
   const [visibleSecrets, setVisibleSecrets] = useState<Record<string, boolean>>({})
   const [isEditModalOpen, setIsEditModalOpen] = useState(false);
@@ -34,6 +34,7 @@ export function LinkedServiceDetail({ service }: LinkedServiceDetailProps) {
 
   const copyToClipboard = (text: string) => {
     navigator.clipboard.writeText(text)
+    markServiceUsed(service.id)
   }
 
   const renderFieldValue = (field: ServiceField, value: string) => {
@@ -24,7 +24,7 @@ export default function AccountView() {
   const { id } = useParams<{ id: string }>()
   const navigate = useNavigate()
   const { t } = useTranslation();
-  const { vault, updateAccount, deleteAccount, linkServicesToAccount } = useVaultStore()
+  const { vault, updateAccount, deleteAccount, linkServicesToAccount, markServiceUsed } = useVaultStore()
   const [editingNotes, setEditingNotes] = useState(false)
   
   const account = vault?.accounts.find((acc) => acc.id === id)
@@ -113,8 +113,9 @@ export default function AccountView() {
     }))
   }
 
-  const copyToClipboard = (text: string) => {
+  const copyToClipboard = (text: string, serviceId: string) => {
     navigator.clipboard.writeText(text)
+    markServiceUsed(serviceId)
   }
   
   const renderFieldValue = (field: ServiceField, value: string) => {
@@ -353,7 +354,7 @@ export default function AccountView() {
                                     <Button
                                       size="sm"
                                       variant="ghost"
-                                      onClick={() => copyToClipboard(value)}
+                                      onClick={() => copyToClipboard(value, service.id)}
                                       className="text-gray-400 hover:text-white"
                                     >
                                       <Copy className="w-4 h-4" />
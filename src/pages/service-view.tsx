@@ -19,7 +19,7 @@ export default function ServiceView() {
   const { id } = useParams<{ id: string }>()
   const navigate = useNavigate()
   const { t } = useTranslation();
-  const { vault, deleteService } = useVaultStore()
+  const { vault, deleteService, markServiceUsed } = useVaultStore()
   const [visibleSecrets, setVisibleSecrets] = useState<Record<string, boolean>>({})
   const [isEditModalOpen, setIsEditModalOpen] = useState(false)
   const [isLinkToAccountModalOpen, setIsLinkToAccountModalOpen] = useState(false)
@@ -78,6 +78,7 @@ export default function ServiceView() {
 
   const copyToClipboard = (text: string) => {
     navigator.clipboard.writeText(text)
+    markServiceUsed(service.id)
   }
   
   const renderFieldValue = (field: ServiceField, value: string) => {
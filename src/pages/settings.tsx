@@ -8,16 +8,20 @@ import { Select, SelectContent, SelectItem, SelectTrigger, SelectValue } from "@
 import { Separator } from "@/components/ui/separator"
 import { Shield, Download, Upload, Trash2, Save, Languages } from "lucide-react"
 import { useVaultStore } from "@/stores/vault-store";
-import type { Settings } from "@/types";
+import type { Settings, ImportMode } from "@/types";
 import { toast } from "sonner";
+import { confirm, open, save } from "@tauri-apps/plugin-dialog";
+
+const VAULT_FILE_FILTERS = [{ name: "AccMan Vault", extensions: ["json"] }];
 
 export default function SettingsPage() {
-    const { vault, updateSettings, changePassword } = useVaultStore();
+    const { vault, updateSettings, changePassword, exportVault, importVault } = useVaultStore();
     const { t, i18n } = useTranslation();
     const [settings, setSettings] = useState<Settings | null>(vault?.settings || null);
     const [currentPassword, setCurrentPassword] = useState("");
     const [newPassword, setNewPassword] = useState("");
     const [confirmPassword, setConfirmPassword] = useState("");
+    const [backupPassword, setBackupPassword] = useState("");
 
     useEffect(() => {
         if(vault?.settings) {
@@ -62,6 +66,42 @@ export default function SettingsPage() {
         }
     }
 
+    const handleExport = async () => {
+        const filePath = await save({ defaultPath: "accman-export.json", filters: VAULT_FILE_FILTERS });
+        if (!filePath) return;
+        try {
+            await exportVault(filePath, backupPassword || undefined);
+            setBackupPassword("");
+        } catch (error) {
+            // Error toast is shown by the API layer
+        }
+    }
+
+    const handleImport = async (mode: ImportMode) => {
+        if (!backupPassword) {
+            toast.error(t('settings.backup.password_required'));
+            return;
+        }
+        const filePath = await open({ multiple: false, directory: false, filters: VAULT_FILE_FILTERS });
+        if (!filePath) return;
+        if (mode === "replace") {
+            const confirmed = await confirm(t('settings.backup.replace_confirm.message'), { title: t('settings.backup.replace_confirm.title') });
+            if (!confirmed) return;
+        }
+        try {
+            const result = await importVault(filePath, backupPassword, mode);
+            setBackupPassword("");
+            toast.success(t('settings.backup.imported', {
+                serviceTypes: result.serviceTypesAdded,
+                services: result.servicesAdded,
+                accounts: result.accountsAdded,
+                skipped: result.skipped,
+            }));
+        } catch (error) {
+            // Error toast is shown by the API layer
+        }
+    }
+
   return (
     <div className="p-6 space-y-6">
       <div className="flex items-center justify-between">
@@ -130,6 +170,27 @@ export default function SettingsPage() {
                 </SelectContent>
               </Select>
             </div>
+            <div className="space-y-2">
+              <Label htmlFor="trashRetention" className="text-gray-300">
+                {t('settings.trash.label')}
+              </Label>
+              <p className="text-sm text-gray-400">{t('settings.trash.description')}</p>
+              <Select
+                value={String(settings?.trashRetentionDays ?? 30)}
+                onValueChange={(value) => handleSettingsChange({ trashRetentionDays: Number(value) })}
+              >
+                <SelectTrigger id="trashRetention" className="w-48 bg-gray-700 border-gray-600 text-white">
+                  <SelectValue />
+                </SelectTrigger>
+                <SelectContent className="bg-gray-700 border-gray-600">
+                    <SelectItem value="7">{t('settings.trash.days', { count: 7 })}</SelectItem>
+                    <SelectItem value="30">{t('settings.trash.days', { count: 30 })}</SelectItem>
+                    <SelectItem value="90">{t('settings.trash.days', { count: 90 })}</SelectItem>
+                    <SelectItem value="365">{t('settings.trash.days', { count: 365 })}</SelectItem>
+                    <SelectItem value="0">{t('settings.trash.forever')}</SelectItem>
+                </SelectContent>
+              </Select>
+            </div>
           </div>
 
           <Separator className="bg-gray-700" />
@@ -172,15 +233,25 @@ export default function SettingsPage() {
           <CardDescription className="text-gray-400">{t('settings.backup.description')}</CardDescription>
         </CardHeader>
         <CardContent className="space-y-4">
+          <div className="space-y-2">
+            <Label htmlFor="backupPassword" className="text-gray-300">
+              {t('settings.backup.password')}
+            </Label>
+            <Input id="backupPassword" type="password" className="bg-gray-700 border-gray-600 text-white" placeholder={t('settings.backup.password_placeholder')} value={backupPassword} onChange={(e) => setBackupPassword(e.target.value)} />
+          </div>
           <div className="flex flex-col sm:flex-row gap-4">
-            <Button variant="outline" className="border-gray-600 text-gray-300 bg-transparent" disabled>
+            <Button variant="outline" className="border-gray-600 text-gray-300 bg-transparent" onClick={handleExport}>
               <Download className="w-4 h-4 mr-2" />
               {t('settings.backup.export_button')}
             </Button>
-            <Button variant="outline" className="border-gray-600 text-gray-300 bg-transparent" disabled>
+            <Button variant="outline" className="border-gray-600 text-gray-300 bg-transparent" onClick={() => handleImport("merge")}>
               <Upload className="w-4 h-4 mr-2" />
               {t('settings.backup.import_button')}
             </Button>
+            <Button variant="outline" className="border-gray-600 text-gray-300 bg-transparent" onClick={() => handleImport("replace")}>
+              <Upload className="w-4 h-4 mr-2" />
+              {t('settings.backup.import_replace_button')}
+            </Button>
           </div>
         </CardContent>
       </Card>
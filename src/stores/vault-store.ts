@@ -2,7 +2,7 @@ import { create } from "zustand";
 import { toast } from "sonner";
 import i18n from "@/i18n";
 import { vaultApi } from "@/api/vault";
-import type { Vault, ServiceType, Service, Account, Settings, BulkCreateRequest } from "@/types";
+import type { Vault, ServiceType, Service, Account, Settings, BulkCreateRequest, VaultProfile, ImportMode, ImportResult, MergePreview, MergeDecision, HistoryEntry, HistoryPreview, RecordKind, TrashedItem, TrashKey, RecordQuery } from "@/types";
 
 export type { Vault, Service, ServiceType, ServiceField, Account, Settings, BulkCreateRequest } from "@/types";
 
@@ -11,6 +11,8 @@ type AppStatus = "loading" | "needs_setup" | "locked" | "unlocked" | "error";
 interface VaultStore {
   appStatus: AppStatus;
   vault: Vault | null;
+  vaults: VaultProfile[];
+  activeVaultId: string | null;
   error: string | null;
   servicesViewMode: "grid" | "table";
   accountsViewMode: "grid" | "list";
@@ -18,6 +20,45 @@ interface VaultStore {
   unlock: (password: string) => Promise<void>;
   createVault: (password: string, settings: Settings, selectedServiceTypeIds: string[]) => Promise<void>;
   lock: () => Promise<void>;
+  restoreBackup: (fileName: string) => Promise<void>;
+  reloadVault: () => Promise<void>;
+
+  // Export / Import
+  exportVault: (filePath: string, exportPassword?: string) => Promise<void>;
+  importVault: (filePath: string, password: string, mode: ImportMode) => Promise<ImportResult>;
+
+  // Merge
+  previewMerge: (filePath: string, password: string) => Promise<MergePreview>;
+  applyMerge: (decisions: MergeDecision[]) => Promise<ImportResult>;
+  cancelMerge: () => Promise<void>;
+
+  // History
+  loadHistory: () => Promise<HistoryEntry[]>;
+  previewVaultAt: (time: string) => Promise<HistoryPreview>;
+  restoreHistoryRecord: (time: string, kind: RecordKind, id: string) => Promise<void>;
+  restoreVaultAt: (time: string) => Promise<void>;
+
+  // Record queries
+  listAccounts: (query?: RecordQuery) => Promise<Account[]>;
+  markServiceUsed: (serviceId: string) => Promise<void>;
+
+  // Undo
+  undo: (steps?: number) => Promise<void>;
+  redo: (steps?: number) => Promise<void>;
+
+  // Trash
+  restoreFromTrash: (kind: RecordKind, id: string) => Promise<void>;
+  purgeTrash: (items: TrashKey[]) => Promise<void>;
+  emptyTrash: () => Promise<void>;
+
+  // Vault registry
+  loadVaults: () => Promise<void>;
+  createVaultProfile: (name: string, vaultPath?: string) => Promise<void>;
+  openVaultProfile: (name: string, vaultPath: string) => Promise<void>;
+  openVaultFile: (vaultPath: string) => Promise<void>;
+  switchVault: (id: string) => Promise<void>;
+  renameVault: (id: string, name: string) => Promise<void>;
+  forgetVault: (id: string) => Promise<void>;
   
   // Settings
   updateSettings: (settings: Settings) => Promise<void>;
@@ -32,6 +73,7 @@ interface VaultStore {
   addService: (service: Service, accountId?: string) => Promise<void>;
   addServices: (services: Service[]) => Promise<void>; // Add this
   updateService: (service: Service) => Promise<void>;
+  restoreFieldValue: (serviceId: string, fieldKey: string, replacedAt: string) => Promise<void>;
   deleteService: (serviceId: string) => Promise<void>;
   deleteServices: (serviceIds: string[]) => Promise<void>;
 
@@ -48,9 +90,19 @@ interface VaultStore {
   resetError: () => void;
 }
 
+export const trashedId = (item: TrashedItem): string => {
+  switch (item.kind) {
+    case 'serviceType': return item.serviceType.id;
+    case 'service': return item.service.id;
+    case 'account': return item.account.id;
+  }
+};
+
 export const useVaultStore = create<VaultStore>((set, get) => ({
   appStatus: "loading",
   vault: null,
+  vaults: [],
+  activeVaultId: null,
   error: null,
   servicesViewMode: "grid",
   accountsViewMode: "grid",
@@ -70,8 +122,11 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
   unlock: async (password: string) => {
     set({ appStatus: "loading", error: null });
     try {
-      const vault = await vaultApi.unlockVault(password);
+      const { vault, kdfUpgraded } = await vaultApi.unlockVault(password);
       set({ appStatus: "unlocked", vault });
+      if (kdfUpgraded) {
+        toast.success(i18n.t('api.success.kdf_upgraded'));
+      }
     } catch (e: any) {
       set({ appStatus: "error", error: e.message });
       throw e; // Re-throw to be caught in the component
@@ -81,7 +136,7 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
   createVault: async (password: string, settings: Settings, selectedServiceTypeIds: string[]) => {
     set({ appStatus: "loading", error: null });
     try {
-      const vault = await vaultApi.createVault(password, settings, selectedServiceTypeIds);
+      const { vault } = await vaultApi.createVault(password, settings, selectedServiceTypeIds);
       set({ appStatus: "unlocked", vault });
     } catch (e: any) {
       set({ appStatus: "error", error: e.message });
@@ -100,6 +155,156 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
     }
   },
   
+  restoreBackup: async (fileName: string) => {
+    await vaultApi.restoreBackup(fileName);
+    set({ appStatus: "locked", vault: null, error: null });
+  },
+
+  reloadVault: async () => {
+    const vault = await vaultApi.reloadVault();
+    set({ vault });
+  },
+
+  exportVault: async (filePath: string, exportPassword?: string) => {
+    await vaultApi.exportVaultBackup(filePath, exportPassword);
+  },
+
+  importVault: async (filePath: string, password: string, mode: ImportMode) => {
+    const result = await vaultApi.importVaultBackup(filePath, password, mode);
+    set({ vault: result.vault });
+    return result;
+  },
+
+  previewMerge: async (filePath: string, password: string) => {
+    return vaultApi.previewVaultMerge(filePath, password);
+  },
+
+  applyMerge: async (decisions: MergeDecision[]) => {
+    const result = await vaultApi.applyVaultMerge(decisions);
+    set({ vault: result.vault });
+    return result;
+  },
+
+  cancelMerge: async () => {
+    await vaultApi.cancelVaultMerge();
+  },
+
+  loadHistory: async () => {
+    return vaultApi.listVaultHistory();
+  },
+
+  previewVaultAt: async (time: string) => {
+    return vaultApi.previewVaultAt(time);
+  },
+
+  restoreHistoryRecord: async (time: string, kind: RecordKind, id: string) => {
+    const vault = await vaultApi.restoreHistoryRecord(time, kind, id);
+    set({ vault });
+  },
+
+  restoreVaultAt: async (time: string) => {
+    const vault = await vaultApi.restoreVaultAt(time);
+    set({ vault });
+  },
+
+  listAccounts: async (query?: RecordQuery) => {
+    return vaultApi.listAccounts(query);
+  },
+
+  // The secret was copied either way, so a failure here is not rethrown.
+  markServiceUsed: async (serviceId: string) => {
+    const lastUsedAt = await vaultApi.markRecordUsed('service', serviceId).catch(() => null);
+    const { vault } = get();
+    if (!vault || !lastUsedAt) return;
+
+    set({
+      vault: {
+        ...vault,
+        services: vault.services.map(s => s.id === serviceId ? { ...s, lastUsedAt } : s),
+      },
+    });
+  },
+
+  undo: async (steps?: number) => {
+    const vault = await vaultApi.undo(steps);
+    set({ vault });
+  },
+
+  redo: async (steps?: number) => {
+    const vault = await vaultApi.redo(steps);
+    set({ vault });
+  },
+
+  restoreFromTrash: async (kind: RecordKind, id: string) => {
+    const vault = await vaultApi.restoreFromTrash(kind, id);
+    set({ vault });
+  },
+
+  purgeTrash: async (items: TrashKey[]) => {
+    const { vault } = get();
+    if (!vault) return;
+
+    await vaultApi.purgeTrash(items);
+    set({
+      vault: {
+        ...vault,
+        trash: (vault.trash ?? []).filter(item => !items.some(key => key.kind === item.kind && key.id === trashedId(item))),
+      },
+    });
+  },
+
+  emptyTrash: async () => {
+    const { vault } = get();
+    if (!vault) return;
+
+    await vaultApi.emptyTrash();
+    set({ vault: { ...vault, trash: [] } });
+  },
+
+  loadVaults: async () => {
+    const { vaults, activeVaultId } = await vaultApi.listVaults();
+    set({ vaults, activeVaultId: activeVaultId ?? null });
+  },
+
+  // Switching locks the open vault; the new one still has to be unlocked or set up.
+  createVaultProfile: async (name: string, vaultPath?: string) => {
+    await vaultApi.createVaultProfile(name, vaultPath);
+    set({ vault: null, error: null });
+    await get().loadVaults();
+    await get().checkInitialStatus();
+  },
+
+  openVaultProfile: async (name: string, vaultPath: string) => {
+    await vaultApi.openVaultProfile(name, vaultPath);
+    set({ vault: null, error: null });
+    await get().loadVaults();
+    await get().checkInitialStatus();
+  },
+
+  openVaultFile: async (vaultPath: string) => {
+    await vaultApi.openVaultFile(vaultPath);
+    set({ vault: null, error: null });
+    await get().loadVaults();
+    await get().checkInitialStatus();
+  },
+
+  switchVault: async (id: string) => {
+    await vaultApi.switchVault(id);
+    set({ vault: null, error: null });
+    await get().loadVaults();
+    await get().checkInitialStatus();
+  },
+
+  renameVault: async (id: string, name: string) => {
+    const renamed = await vaultApi.renameVault(id, name);
+    set({ vaults: get().vaults.map(v => v.id === id ? renamed : v) });
+  },
+
+  forgetVault: async (id: string) => {
+    await vaultApi.forgetVault(id);
+    set({ vaults: get().vaults.filter(v => v.id !== id) });
+  },
+
   updateSettings: async (settings: Settings) => {
     const { vault } = get();
     if (!vault) return;
@@ -117,11 +322,11 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
     const { vault } = get();
     if (!vault) return;
     
-    await vaultApi.addServiceType(serviceType);
+    const saved = await vaultApi.addServiceType(serviceType);
     set({
       vault: {
         ...vault,
-        serviceTypes: [...vault.serviceTypes, serviceType],
+        serviceTypes: [...vault.serviceTypes, saved],
       },
     });
   },
@@ -134,11 +339,11 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
       const { vault } = get();
       if (!vault) return;
       
-      await vaultApi.updateServiceType(serviceType);
+      const saved = await vaultApi.updateServiceType(serviceType);
       set({
           vault: {
               ...vault,
-              serviceTypes: vault.serviceTypes.map(st => st.id === serviceType.id ? serviceType : st),
+              serviceTypes: vault.serviceTypes.map(st => st.id === saved.id ? saved : st),
           }
       });
   },
@@ -148,23 +353,19 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
       if (!vault) return;
       
       await vaultApi.deleteServiceType(serviceTypeId);
-      set({
-          vault: {
-              ...vault,
-              serviceTypes: vault.serviceTypes.filter(st => st.id !== serviceTypeId),
-          }
-      });
+      // Deleted records move to the trash, so take the vault as the backend has it.
+      set({ vault: await vaultApi.getVault() });
   },
 
   addService: async (service: Service, accountId?: string) => {
     const { vault } = get();
     if (!vault) return;
 
-    await vaultApi.addService(service, accountId);
+    const saved = await vaultApi.addService(service, accountId);
     set({
       vault: {
         ...vault,
-        services: [...vault.services, service],
+        services: [...vault.services, saved],
         accounts: accountId ? vault.accounts.map(acc => {
           if (acc.id === accountId) {
             const newLinkedServices = [...acc.linkedServices, service.id];
@@ -180,11 +381,11 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
     const { vault } = get();
     if (!vault) return;
 
-    await vaultApi.addServices(services);
+    const saved = await vaultApi.addServices(services);
     set({
       vault: {
         ...vault,
-        services: [...vault.services, ...services],
+        services: [...vault.services, ...saved],
       },
     });
   },
@@ -193,50 +394,54 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
     const { vault } = get();
     if (!vault) return;
 
-    await vaultApi.updateService(service);
+    // The saved service carries the updated field history.
+    const saved = await vaultApi.updateService(service);
     set({
       vault: {
         ...vault,
-        services: vault.services.map(s => s.id === service.id ? service : s),
+        services: vault.services.map(s => s.id === saved.id ? saved : s),
       },
     });
   },
 
-  deleteService: async (serviceId: string) => {
+  restoreFieldValue: async (serviceId: string, fieldKey: string, replacedAt: string) => {
     const { vault } = get();
     if (!vault) return;
 
-    await vaultApi.deleteService(serviceId);
+    const saved = await vaultApi.restoreFieldValue(serviceId, fieldKey, replacedAt);
     set({
       vault: {
         ...vault,
-        services: vault.services.filter(s => s.id !== serviceId),
+        services: vault.services.map(s => s.id === saved.id ? saved : s),
       },
     });
   },
 
+  deleteService: async (serviceId: string) => {
+    const { vault } = get();
+    if (!vault) return;
+
+    await vaultApi.deleteService(serviceId);
+    set({ vault: await vaultApi.getVault() });
+  },
+
   deleteServices: async (serviceIds: string[]) => {
     const { vault } = get();
     if (!vault) return;
 
     await vaultApi.deleteServices(serviceIds);
-    set({
-      vault: {
-        ...vault,
-        services: vault.services.filter(s => !serviceIds.includes(s.id)),
-      },
-    });
+    set({ vault: await vaultApi.getVault() });
   },
 
   addAccount: async (account: Account) => {
     const { vault } = get();
     if (!vault) return;
 
-    await vaultApi.addAccount(account);
+    const saved = await vaultApi.addAccount(account);
     set({
       vault: {
         ...vault,
-        accounts: [...vault.accounts, account],
+        accounts: [...vault.accounts, saved],
       },
     });
   },
@@ -245,11 +450,11 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
     const { vault } = get();
     if (!vault) return;
 
-    await vaultApi.updateAccount(account);
+    const saved = await vaultApi.updateAccount(account);
     set({
       vault: {
         ...vault,
-        accounts: vault.accounts.map(a => a.id === account.id ? account : a),
+        accounts: vault.accounts.map(a => a.id === saved.id ? saved : a),
       },
     });
   },
@@ -259,30 +464,18 @@ export const useVaultStore = create<VaultStore>((set, get) => ({
     if (!vault) return;
 
     await vaultApi.deleteAccount(accountId);
-    set({
-      vault: {
-        ...vault,
-        accounts: vault.accounts.filter(a => a.id !== accountId),
-      },
-    });
+    set({ vault: await vaultApi.getVault() });
   },
 
   linkServicesToAccount: async (accountId: string, serviceIds: string[]) => {
     const { vault } = get();
     if (!vault) return;
 
-    await vaultApi.linkServicesToAccount(accountId, serviceIds);
+    const saved = await vaultApi.linkServicesToAccount(accountId, serviceIds);
     set({
         vault: {
             ...vault,
-            accounts: vault.accounts.map(acc => {
-                if (acc.id === accountId) {
-                    const newLinkedServices = [...acc.linkedServices, ...serviceIds];
-                    // Remove duplicates
-                    return { ...acc, linkedServices: [...new Set(newLinkedServices)] };
-                }
-                return acc;
-            })
+            accounts: vault.accounts.map(acc => acc.id === saved.id ? saved : acc)
         }
     })
   },
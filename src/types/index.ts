@@ -13,6 +13,9 @@ export interface ServiceType {
   name: string
   icon: string
   fields: ServiceField[]
+  createdAt?: string
+  updatedAt?: string
+  lastUsedAt?: string
 }
 
 export interface Service {
@@ -21,6 +24,17 @@ export interface Service {
   label: string
   data: Record<string, string>
   tags: string[]
+  /** Earlier values of each field, oldest first. Maintained by the backend. */
+  fieldHistory?: Record<string, FieldValue[]>
+  /** Timestamps are maintained by the backend. */
+  createdAt?: string
+  updatedAt?: string
+  lastUsedAt?: string
+}
+
+export interface FieldValue {
+  value: string
+  replacedAt: string
 }
 
 export interface Account {
@@ -29,10 +43,54 @@ export interface Account {
   notes: string
   tags: string[]
   linkedServices: string[]
+  createdAt?: string
+  updatedAt?: string
+  lastUsedAt?: string
+}
+
+export type TimestampField = 'createdAt' | 'updatedAt' | 'lastUsedAt'
+
+export interface RecordQuery {
+  sortBy?: TimestampField
+  descending?: boolean
+  /** Only records neither changed nor used in this many days. */
+  untouchedForDays?: number
 }
 
 export interface Settings {
     autoLockMinutes: number;
+    backup?: BackupSettings;
+    trashRetentionDays?: number;
+}
+
+export interface BackupSettings {
+    enabled: boolean;
+    keepLast: number;
+    keepDailyDays: number;
+}
+
+export interface BackupInfo {
+  fileName: string
+  createdAt: string
+  size: number
+}
+
+export type KdfParams =
+  | { algorithm: "scrypt"; logN: number; r: number; p: number }
+  | { algorithm: "argon2id"; memoryKib: number; iterations: number; parallelism: number }
+
+export type KdfAlgorithm = "scrypt" | "argon2id"
+
+export type Cipher = "aes-256-gcm" | "chacha20-poly1305" | "xchacha20-poly1305"
+
+export interface EncryptionSettings {
+  kdf?: KdfParams
+  cipher?: Cipher
+}
+
+export interface KdfCalibration {
+  kdf: KdfParams
+  measuredMs: number
 }
 
 export interface Vault {
@@ -41,6 +99,127 @@ export interface Vault {
   services: Service[]
   accounts: Account[]
   settings: Settings;
+  trash?: TrashedItem[]
+}
+
+export type TrashedItem =
+  | { kind: 'serviceType'; serviceType: ServiceType; deletedAt: string }
+  | { kind: 'service'; service: Service; linkedAccountIds: string[]; deletedAt: string }
+  | { kind: 'account'; account: Account; deletedAt: string }
+
+export interface TrashKey {
+  kind: RecordKind
+  id: string
+}
+
+export interface CreateVaultResult {
+  vault: Vault
+  recoveryKey?: string
+}
+
+export type ImportMode = 'replace' | 'merge'
+
+export interface ImportResult {
+  vault: Vault
+  serviceTypesAdded: number
+  servicesAdded: number
+  accountsAdded: number
+  replaced: number
+  skipped: number
+}
+
+export type RecordKind = 'serviceType' | 'service' | 'account'
+
+export interface MergeItem {
+  kind: RecordKind
+  theirId: string
+  myId?: string
+  label: string
+  matchedByLabel: boolean
+  status: 'new' | 'identical' | 'conflict' | 'inTrash'
+}
+
+export interface MergePreview {
+  items: MergeItem[]
+  new: number
+  identical: number
+  conflicts: number
+  inTrash: number
+}
+
+export interface MergeDecision {
+  kind: RecordKind
+  theirId: string
+  resolution: 'keepMine' | 'keepTheirs' | 'keepBoth'
+}
+
+export type VaultOperation =
+  | { type: 'updateSettings'; settings: Settings }
+  | { type: 'addServiceType'; serviceType: ServiceType }
+  | { type: 'updateServiceType'; serviceType: ServiceType }
+  | { type: 'deleteServiceType'; serviceTypeId: string }
+  | { type: 'trashServiceType'; serviceTypeId: string; deletedAt: string }
+  | { type: 'addService'; service: Service; accountId?: string }
+  | { type: 'addServices'; services: Service[] }
+  | { type: 'updateService'; service: Service }
+  | { type: 'deleteService'; serviceId: string }
+  | { type: 'deleteServices'; serviceIds: string[] }
+  | { type: 'trashServices'; serviceIds: string[]; deletedAt: string }
+  | { type: 'addAccount'; account: Account }
+  | { type: 'addAccounts'; accounts: Account[] }
+  | { type: 'updateAccount'; account: Account }
+  | { type: 'deleteAccount'; accountId: string }
+  | { type: 'trashAccount'; accountId: string; deletedAt: string }
+  | { type: 'linkServices'; accountId: string; serviceIds: string[] }
+  | { type: 'setLastUsed'; kind: RecordKind; id: string; lastUsedAt?: string }
+  | { type: 'restoreFromTrash'; item: TrashKey }
+  | { type: 'purgeTrash'; items: TrashKey[] }
+  | { type: 'returnToTrash'; items: TrashedItem[] }
+  | { type: 'batch'; operations: VaultOperation[] }
+
+export interface UndoState {
+  undoSteps: number
+  redoSteps: number
+}
+
+export interface HistoryEntry {
+  seq: number
+  recordedAt: string
+  operation: VaultOperation
+}
+
+export interface RecordChange {
+  kind: RecordKind
+  id: string
+  label: string
+  change: 'added' | 'removed' | 'modified'
+}
+
+export interface HistoryPreview {
+  vault: Vault
+  changes: RecordChange[]
+}
+
+export interface VaultProfile {
+  id: string
+  name: string
+  path: string
+}
+
+export interface VaultRegistry {
+  vaults: VaultProfile[]
+  activeVaultId?: string
+}
+
+export interface UnlockResult {
+  vault: Vault
+  kdfUpgraded: boolean
+}
+
+export interface RecoveryShares {
+  threshold: number
+  shares: string[]
+  files: string[]
 }
 
 export interface BulkAccountConfig {